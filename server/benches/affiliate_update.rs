@@ -0,0 +1,81 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use discovery_api::{
+    cluster_server::Cluster,
+    tonic::{transport::server::TcpConnectInfo, Request},
+    AffiliateUpdateRequest,
+};
+use std::{sync::Arc, thread::available_parallelism};
+use tokio::runtime::{Builder, Runtime};
+
+use talos_discovery_service::{DiscoveryService, ServiceOptions, SystemClock};
+
+const CLUSTERS: usize = 256;
+const AFFILIATES: usize = 8;
+
+fn update_request(cluster: usize, affiliate: usize) -> Request<AffiliateUpdateRequest> {
+    let mut request = Request::new(AffiliateUpdateRequest {
+        cluster_id: format!("cluster-{cluster}"),
+        affiliate_id: format!("affiliate-{affiliate}"),
+        affiliate_data: Some(vec![0; 256]),
+        affiliate_endpoints: vec![vec![0; 16]],
+        ttl: Some(prost_types::Duration { seconds: 600, nanos: 0 }),
+    });
+    // handlers log the address of the client
+    request.extensions_mut().insert(TcpConnectInfo {
+        local_addr: None,
+        remote_addr: Some(([127, 0, 0, 1], 50000).into()),
+    });
+    request
+}
+
+// every cluster is updated by its own task, so the tasks only contend on the cluster store
+async fn update_clusters(service: &DiscoveryService) {
+    let tasks = (0..CLUSTERS)
+        .map(|cluster| {
+            let service = service.clone();
+            tokio::spawn(async move {
+                for affiliate in 0..AFFILIATES {
+                    service
+                        .affiliate_update(update_request(cluster, affiliate))
+                        .await
+                        .unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+fn runtime(threads: usize) -> Runtime {
+    Builder::new_multi_thread()
+        .worker_threads(threads)
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+fn affiliate_update(c: &mut Criterion) {
+    let mut threads = vec![1, 2, 4, available_parallelism().map_or(1, usize::from)];
+    threads.sort();
+    threads.dedup();
+
+    let mut group = c.benchmark_group("affiliate_update");
+    group.throughput(Throughput::Elements((CLUSTERS * AFFILIATES) as u64));
+    for threads in threads {
+        let runtime = runtime(threads);
+        let service = runtime
+            .block_on(DiscoveryService::new(ServiceOptions::default(), Arc::new(SystemClock)))
+            .unwrap();
+
+        group.bench_with_input(BenchmarkId::new("threads", threads), &service, |b, service| {
+            b.iter(|| runtime.block_on(update_clusters(service)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, affiliate_update);
+criterion_main!(benches);
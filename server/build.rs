@@ -0,0 +1,30 @@
+use tonic_build::manual::{Builder, Method, Service};
+
+// the replication service is internal to this server, so it's defined in Rust instead of a proto file
+fn main() {
+    let replication = Service::builder()
+        .name("Replication")
+        .package("talos.discovery.replication")
+        .method(
+            Method::builder()
+                .name("subscribe")
+                .route_name("Subscribe")
+                .input_type("crate::replication::SubscribeRequest")
+                .output_type("crate::replication::ReplicationEvent")
+                .codec_path("tonic::codec::ProstCodec")
+                .server_streaming()
+                .build(),
+        )
+        .method(
+            Method::builder()
+                .name("promote")
+                .route_name("Promote")
+                .input_type("crate::replication::PromoteRequest")
+                .output_type("crate::replication::PromoteResponse")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .build();
+
+    Builder::new().compile(&[replication]);
+}
@@ -0,0 +1,202 @@
+use std::{
+    ffi::OsString,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::{
+    fs::{self, File},
+    io::AsyncWriteExt,
+    sync::watch,
+};
+use tracing::{info, warn};
+
+/// Rotating generations of a backup file.
+///
+/// The newest generation is written to `path`, older ones get the generation as suffix, e.g. `path.1`. Every
+/// generation is written to a temporary file first and atomically renamed, so a crash never leaves a truncated backup
+/// behind.
+#[derive(Clone, Debug)]
+pub(crate) struct BackupFiles {
+    path: PathBuf,
+    generations: usize,
+}
+
+impl BackupFiles {
+    pub fn new(path: PathBuf, generations: usize) -> Self {
+        Self {
+            path,
+            generations: generations.max(1),
+        }
+    }
+
+    fn with_suffix(&self, suffix: &str) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+
+    fn generation(&self, generation: usize) -> PathBuf {
+        match generation {
+            0 => self.path.clone(),
+            generation => self.with_suffix(&format!(".{generation}")),
+        }
+    }
+
+    pub async fn write(&self, contents: &[u8]) -> anyhow::Result<()> {
+        let tmp_path = self.with_suffix(".tmp");
+
+        let mut file = File::create(&tmp_path).await?;
+        file.write_all(contents).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        for generation in (1..self.generations).rev() {
+            match fs::rename(self.generation(generation - 1), self.generation(generation)).await {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => (),
+            }
+        }
+        fs::rename(&tmp_path, &self.path).await?;
+
+        // persist the renames
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir).await?.sync_all().await?;
+
+        Ok(())
+    }
+
+    /// Returns the newest generation which can be parsed, or `None` if there is no backup at all.
+    pub async fn load<T>(&self, parse: impl Fn(&[u8]) -> anyhow::Result<T>) -> anyhow::Result<Option<T>> {
+        let mut last_err = None;
+
+        for generation in 0..self.generations {
+            let path = self.generation(generation);
+            let parsed = match fs::read(&path).await {
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => Err(err.into()),
+                Ok(contents) => parse(&contents),
+            };
+            match parsed {
+                Ok(parsed) => {
+                    info!("Loading backup {}", path.display());
+                    return Ok(Some(parsed));
+                }
+                Err(err) => {
+                    warn!("Skipping invalid backup {}: {}", path.display(), err);
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        match last_err {
+            Some(err) => Err(err.context(format!("no valid backup generation of {}", self.path.display()))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Health of the persistence, degraded while backups are failing.
+pub struct BackupHealth {
+    failures: AtomicU64,
+    consecutive_failures: AtomicU64,
+    degraded: watch::Sender<bool>,
+}
+
+impl Default for BackupHealth {
+    fn default() -> Self {
+        Self {
+            failures: AtomicU64::new(0),
+            consecutive_failures: AtomicU64::new(0),
+            degraded: watch::Sender::new(false),
+        }
+    }
+}
+
+impl BackupHealth {
+    /// Name of the service in the gRPC health checks.
+    pub const SERVICE_NAME: &str = "persistence";
+
+    /// Records a failed backup and returns the number of consecutive failures.
+    pub fn failed(&self) -> u64 {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        let consecutive_failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        self.degraded
+            .send_if_modified(|degraded| !std::mem::replace(degraded, true));
+        consecutive_failures
+    }
+
+    pub fn succeeded(&self) {
+        let consecutive_failures = self.consecutive_failures.swap(0, Ordering::Relaxed);
+        if consecutive_failures > 0 {
+            info!("Backup succeeded after {} failed attempts", consecutive_failures);
+        }
+        self.degraded
+            .send_if_modified(|degraded| std::mem::replace(degraded, false));
+    }
+
+    /// Total number of failed backups.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.degraded.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::temp_dir;
+    use tempfile::TempDir;
+
+    fn backup_files(generations: usize) -> (TempDir, BackupFiles) {
+        let dir = temp_dir();
+        let files = BackupFiles::new(dir.path().join("backup.json"), generations);
+        (dir, files)
+    }
+
+    fn parse(contents: &[u8]) -> anyhow::Result<String> {
+        Ok(std::str::from_utf8(contents)?.to_string())
+    }
+
+    #[tokio::test]
+    async fn writes_rotate_generations() {
+        let (_dir, files) = backup_files(3);
+        for contents in ["first", "second", "third", "fourth"] {
+            files.write(contents.as_bytes()).await.unwrap();
+        }
+
+        let generations = (0..4)
+            .map(|generation| std::fs::read_to_string(files.generation(generation)).ok())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            generations,
+            vec![
+                Some("fourth".to_string()),
+                Some("third".to_string()),
+                Some("second".to_string()),
+                None
+            ]
+        );
+        assert!(!files.with_suffix(".tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn load_falls_back_to_older_generation() {
+        let (_dir, files) = backup_files(3);
+        assert!(files.load(parse).await.unwrap().is_none());
+
+        files.write(b"valid").await.unwrap();
+        files.write(&[0xff, 0xfe]).await.unwrap();
+        assert_eq!(files.load(parse).await.unwrap(), Some("valid".to_string()));
+
+        files.write(&[0xff, 0xfe]).await.unwrap();
+        files.write(&[0xff, 0xfe]).await.unwrap();
+        assert!(files.load(parse).await.is_err());
+    }
+}
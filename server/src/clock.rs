@@ -0,0 +1,241 @@
+use discovery_api::tonic::async_trait;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::time::{self, Instant};
+use tracing::{info, warn};
+
+/// Source of time for expirations and the background loops.
+#[async_trait]
+pub trait Clock: Send + Sync {
+    /// Monotonic time, used for all expirations.
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time, only used for backups.
+    fn wall(&self) -> SystemTime;
+
+    async fn sleep_until(&self, deadline: Instant);
+
+    fn time_base(&self) -> TimeBase {
+        TimeBase {
+            instant: self.now(),
+            wall: self.wall(),
+        }
+    }
+}
+
+/// Clock of the host.
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        time::sleep_until(deadline).await
+    }
+}
+
+/// Ticks every `period`, starting immediately like `tokio::time::interval`.
+pub(crate) struct Interval {
+    clock: Arc<dyn Clock>,
+    next: Instant,
+    period: Duration,
+}
+
+impl Interval {
+    pub fn new(clock: Arc<dyn Clock>, period: Duration) -> Self {
+        Self {
+            next: clock.now(),
+            clock,
+            period,
+        }
+    }
+
+    pub async fn tick(&mut self) {
+        self.clock.sleep_until(self.next).await;
+        self.next += self.period;
+    }
+}
+
+/// Pair of a monotonic and a wall-clock time point taken at the same moment.
+///
+/// Expirations are kept on the monotonic clock, so steps of the system clock don't affect them. Only backups carry
+/// wall-clock times, which are converted relative to a time base.
+#[derive(Clone, Copy, Debug)]
+pub struct TimeBase {
+    pub instant: Instant,
+    pub wall: SystemTime,
+}
+
+impl TimeBase {
+    /// Time base to restore the expirations of a snapshot taken at `snapshot_time`.
+    ///
+    /// If the system clock is behind the snapshot, it has been stepped back in the meantime. The expirations are then
+    /// restored with the remaining TTLs they had at the time of the snapshot. If it's ahead by more than `max_ttl`, all
+    /// expirations have passed, either because of a long downtime or because the clock was stepped forward.
+    pub fn restore(self, snapshot_time: SystemTime, max_ttl: Duration) -> Self {
+        match self.wall.duration_since(snapshot_time) {
+            Ok(elapsed) if elapsed > max_ttl => {
+                warn!(
+                    "Clock skew suspected: system clock is {}s ahead of the backup snapshot, more than the maximum TTL, \
+                     so all restored affiliates are expired",
+                    elapsed.as_secs()
+                );
+                self
+            }
+            Ok(elapsed) => {
+                info!("Restoring snapshot taken {}s ago", elapsed.as_secs());
+                self
+            }
+            Err(err) => {
+                warn!(
+                    "Clock skew detected: system clock is {}s behind the backup snapshot, restoring remaining TTLs \
+                     as of the snapshot",
+                    err.duration().as_secs()
+                );
+                Self {
+                    instant: self.instant,
+                    wall: snapshot_time,
+                }
+            }
+        }
+    }
+
+    pub fn wall_of(&self, instant: Instant) -> SystemTime {
+        match instant.checked_duration_since(self.instant) {
+            Some(remaining) => self.wall + remaining,
+            None => self.wall - (self.instant - instant),
+        }
+    }
+
+    /// Converts a wall-clock time, times in the past are mapped to the time base itself.
+    pub fn instant_of(&self, wall: SystemTime) -> Instant {
+        self.instant + wall.duration_since(self.wall).unwrap_or(Duration::ZERO)
+    }
+}
+
+/// Clock which only advances when told to.
+#[cfg(test)]
+pub(crate) struct ManualClock {
+    start: Instant,
+    wall_start: SystemTime,
+    elapsed: tokio::sync::watch::Sender<Duration>,
+    // deadlines of all pending sleeps
+    sleepers: tokio::sync::watch::Sender<Vec<Instant>>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            start: Instant::now(),
+            wall_start: SystemTime::now(),
+            elapsed: tokio::sync::watch::Sender::new(Duration::ZERO),
+            sleepers: tokio::sync::watch::Sender::new(Vec::new()),
+        })
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.send_modify(|elapsed| *elapsed += duration);
+    }
+
+    /// Waits until a task sleeps until `deadline`, which means it's done with everything due before.
+    pub async fn sleeping_until(&self, deadline: Instant) {
+        let _ = self
+            .sleepers
+            .subscribe()
+            .wait_for(|sleepers| sleepers.contains(&deadline))
+            .await;
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.borrow()
+    }
+
+    fn wall(&self) -> SystemTime {
+        self.wall_start + *self.elapsed.borrow()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        // deregisters the sleep even if the future is dropped before the deadline
+        struct Sleeper<'a>(&'a tokio::sync::watch::Sender<Vec<Instant>>, Instant);
+        impl Drop for Sleeper<'_> {
+            fn drop(&mut self) {
+                self.0.send_modify(|sleepers| {
+                    if let Some(i) = sleepers.iter().position(|deadline| *deadline == self.1) {
+                        sleepers.swap_remove(i);
+                    }
+                });
+            }
+        }
+
+        self.sleepers.send_modify(|sleepers| sleepers.push(deadline));
+        let _sleeper = Sleeper(&self.sleepers, deadline);
+
+        let _ = self
+            .elapsed
+            .subscribe()
+            .wait_for(|elapsed| self.start + *elapsed >= deadline)
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversion_keeps_remaining_time() {
+        let base = SystemClock.time_base();
+        let expiration = base.instant + Duration::from_secs(60);
+
+        assert_eq!(base.instant_of(base.wall_of(expiration)), expiration);
+        assert_eq!(base.instant_of(base.wall - Duration::from_secs(60)), base.instant);
+    }
+
+    #[test]
+    fn restore_before_snapshot_keeps_remaining_ttl() {
+        let snapshot_time = SystemTime::now() + Duration::from_secs(3600);
+        let base = SystemClock.time_base().restore(snapshot_time, Duration::from_secs(60));
+
+        let expiration = base.instant_of(snapshot_time + Duration::from_secs(60));
+        assert_eq!(expiration - base.instant, Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn interval_ticks_with_manual_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let period = Duration::from_secs(10);
+
+        let ticks = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut interval = Interval::new(clock.clone(), period);
+        let task_ticks = ticks.clone();
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                task_ticks.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        });
+
+        clock.sleeping_until(start + period).await;
+        assert_eq!(ticks.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        clock.advance(period - Duration::from_secs(1));
+        clock.advance(Duration::from_secs(1));
+        clock.sleeping_until(start + period * 2).await;
+        assert_eq!(ticks.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+}
@@ -1,37 +1,186 @@
 use chrono::{DateTime, Timelike, Utc};
-use serde::{ser::SerializeStruct, Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
     fmt,
     num::TryFromIntError,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime},
 };
-use tokio::sync::{
-    broadcast::Sender,
-    mpsc::{self, Receiver},
+use tokio::{
+    sync::{
+        broadcast::{error::RecvError, Sender},
+        mpsc::{self, Receiver},
+    },
+    time::Instant,
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 use discovery_api::{self, tonic::Status, AffiliateUpdateRequest, WatchResponse};
 
+use crate::{
+    clock::{Clock, TimeBase},
+    limits::Limits,
+    snapshot::{AffiliateBackup, ClusterBackup, EndpointBackup},
+    store::SharedCluster,
+};
+
 pub(crate) type ClusterId = String;
-type AffiliateId = String;
+pub(crate) type AffiliateId = String;
 
 pub(crate) struct TalosCluster {
     pub(crate) id: ClusterId,
     affiliates: HashMap<AffiliateId, Affiliate>,
+    // size of all affiliates, kept up to date so updates don't have to sum it up
+    bytes: usize,
     watch_broadcaster: Sender<WatchResponse>,
+    lag_events: Arc<AtomicU64>,
+    limits: Limits,
+    clock: Arc<dyn Clock>,
+    // deadlines of affiliates and endpoints, entries for refreshed or deleted affiliates are skipped when due
+    expirations: BinaryHeap<Reverse<(Instant, AffiliateId)>>,
+    grace_period: Option<GracePeriod>,
+    // explicitly deleted affiliates with the time of their deletion, so older updates merged from peers don't revive
+    // them
+    tombstones: HashMap<AffiliateId, SystemTime>,
+}
+
+// restored affiliates which haven't re-registered yet aren't expired before the end of the grace period
+struct GracePeriod {
+    until: Instant,
+    pending: HashSet<AffiliateId>,
+}
+
+/// Behaviour of a watch stream which fell behind the broadcast buffer.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum LagPolicy {
+    /// Send a fresh snapshot of the cluster and continue with new updates
+    #[default]
+    Resync,
+    /// Close the watch stream with an error status
+    Close,
 }
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone)]
 pub(crate) struct Affiliate {
     // part of gRPC message Affiliate
     id: AffiliateId,
     // part of gRPC message Affiliate
     data: Vec<u8>,
     // part of gRPC message Affiliate
-    endpoints: Vec<Vec<u8>>,
-    expiration: SystemTime,
+    endpoints: Vec<Endpoint>,
+    // monotonic, so steps of the system clock don't expire affiliates early or keep them alive
+    expiration: Instant,
+    // wall-clock time of the last write, compared across instances when merging their states
+    updated: SystemTime,
+}
+
+#[derive(Clone)]
+pub(crate) struct Endpoint {
+    // part of gRPC message Affiliate
+    data: Vec<u8>,
+    // endpoints expire independently from their affiliate
+    expiration: Instant,
+}
+
+impl Affiliate {
+    fn new(id: AffiliateId, expiration: Instant) -> Affiliate {
+        Affiliate {
+            id,
+            data: Vec::new(),
+            endpoints: Vec::new(),
+            expiration,
+            updated: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    /// Adds new endpoints to the set of known endpoints and refreshes the expiration of already known ones.
+    fn merge_endpoints(&mut self, endpoints: &[Vec<u8>], expiration: Instant) {
+        for endpoint in endpoints {
+            match self.endpoints.iter_mut().find(|existing| existing.data == *endpoint) {
+                Some(existing) => existing.expiration = existing.expiration.max(expiration),
+                None => self.endpoints.push(Endpoint {
+                    data: endpoint.clone(),
+                    expiration,
+                }),
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.id.len() + self.data.len() + self.endpoints.iter().map(|endpoint| endpoint.data.len()).sum::<usize>()
+    }
+
+    pub fn backup(&self, base: &TimeBase) -> AffiliateBackup {
+        AffiliateBackup {
+            id: self.id.clone(),
+            data: self.data.clone(),
+            endpoints: self
+                .endpoints
+                .iter()
+                .map(|endpoint| EndpointBackup {
+                    data: endpoint.data.clone(),
+                    expiration: Some(base.wall_of(endpoint.expiration).into()),
+                })
+                .collect(),
+            expiration: Some(base.wall_of(self.expiration).into()),
+            updated: Some(self.updated.into()),
+        }
+    }
+
+    fn restore(backup: AffiliateBackup, base: &TimeBase) -> Affiliate {
+        // missing or invalid expirations are restored as already expired
+        let restore_expiration = |expiration: Option<prost_types::Timestamp>| {
+            base.instant_of(
+                expiration
+                    .and_then(|expiration| SystemTime::try_from(expiration).ok())
+                    .unwrap_or(SystemTime::UNIX_EPOCH),
+            )
+        };
+
+        Affiliate {
+            id: backup.id,
+            data: backup.data,
+            endpoints: backup
+                .endpoints
+                .into_iter()
+                .map(|endpoint| Endpoint {
+                    data: endpoint.data,
+                    expiration: restore_expiration(endpoint.expiration),
+                })
+                .collect(),
+            expiration: restore_expiration(backup.expiration),
+            updated: backup
+                .updated
+                .and_then(|updated| SystemTime::try_from(updated).ok())
+                .unwrap_or(SystemTime::UNIX_EPOCH),
+        }
+    }
+
+    // the latest write wins, concurrent writes at the same time are ordered by their content, so every instance picks
+    // the same state
+    fn version(&self) -> (SystemTime, &[u8], Vec<&[u8]>) {
+        (
+            self.updated,
+            &self.data,
+            self.endpoints.iter().map(|endpoint| endpoint.data.as_slice()).collect(),
+        )
+    }
+
+    // the oldest writes are evicted first when replicated affiliates exceed the limits of a cluster
+    fn write_order(&self) -> (SystemTime, &AffiliateId) {
+        (self.updated, &self.id)
+    }
+
+    /// Removes expired endpoints and returns whether any endpoint was removed.
+    fn prune_endpoints(&mut self, now: Instant) -> bool {
+        let before_len = self.endpoints.len();
+        self.endpoints.retain(|endpoint| endpoint.expiration > now);
+        before_len != self.endpoints.len()
+    }
 }
 
 impl From<Affiliate> for discovery_api::Affiliate {
@@ -39,52 +188,227 @@ impl From<Affiliate> for discovery_api::Affiliate {
         discovery_api::Affiliate {
             id: val.id,
             data: val.data,
-            endpoints: val.endpoints,
+            endpoints: val.endpoints.into_iter().map(|endpoint| endpoint.data).collect(),
         }
     }
 }
 
 impl TalosCluster {
-    const BUFFER_SIZE: usize = 64;
-    // XXX: custom extension
-    pub const MAX_IDENTIFIER_LENGTH: usize = 256;
-    // XXX: custom extension
-    pub const MAX_PAYLOAD_LENGTH: usize = 512 * 1024;
-    // XXX: custom extension
-    pub const MAX_TTL_DURATION: Duration = Duration::from_secs(2 * 60 * 60); // 2 hours
-
-    pub fn new(cluster_id: ClusterId) -> TalosCluster {
+    pub fn new(cluster_id: ClusterId, limits: Limits, clock: Arc<dyn Clock>) -> TalosCluster {
+        Self::with_affiliates(cluster_id, HashMap::new(), limits, clock)
+    }
+
+    pub fn from_backup(backup: ClusterBackup, base: &TimeBase, limits: Limits, clock: Arc<dyn Clock>) -> TalosCluster {
+        let affiliates = backup
+            .affiliates
+            .into_iter()
+            .map(|affiliate| (affiliate.id.clone(), Affiliate::restore(affiliate, base)))
+            .collect();
+
+        Self::with_affiliates(backup.id, affiliates, limits, clock)
+    }
+
+    /// Keeps the current affiliates for at least `period`, or until all of them have re-registered.
+    pub fn start_grace_period(&mut self, period: Duration) {
+        let pending = self.affiliates.keys().cloned().collect::<HashSet<_>>();
+        if period.is_zero() || pending.is_empty() {
+            return;
+        }
+
+        let until = self.clock.now() + period;
+        for affiliate_id in &pending {
+            self.expirations.push(Reverse((until, affiliate_id.clone())));
+        }
+
+        info!(
+            "Keeping {} restored affiliates of cluster {} for {}s",
+            pending.len(),
+            self.id,
+            period.as_secs()
+        );
+        self.grace_period = Some(GracePeriod { until, pending });
+    }
+
+    fn end_grace_period(&mut self, affiliate_id: &AffiliateId) {
+        let Some(grace_period) = &mut self.grace_period else {
+            return;
+        };
+
+        grace_period.pending.remove(affiliate_id);
+        if grace_period.pending.is_empty() {
+            info!("Grace period of cluster {} ended early", self.id);
+            self.grace_period = None;
+        }
+    }
+
+    /// Returns the state of the cluster with expirations as wall-clock times relative to `base`.
+    pub fn backup(&self, base: &TimeBase) -> ClusterBackup {
+        ClusterBackup {
+            id: self.id.clone(),
+            affiliates: self
+                .affiliates
+                .values()
+                .map(|affiliate| affiliate.backup(base))
+                .collect(),
+        }
+    }
+
+    /// Returns the state of an affiliate as written to the journal.
+    pub fn affiliate_backup(&self, affiliate_id: &AffiliateId) -> Option<AffiliateBackup> {
+        let base = self.clock.time_base();
+        self.affiliates
+            .get(affiliate_id)
+            .map(|affiliate| affiliate.backup(&base))
+    }
+
+    fn with_affiliates(
+        cluster_id: ClusterId,
+        affiliates: HashMap<AffiliateId, Affiliate>,
+        limits: Limits,
+        clock: Arc<dyn Clock>,
+    ) -> TalosCluster {
+        let expirations = affiliates
+            .values()
+            .flat_map(|affiliate| {
+                std::iter::once(affiliate.expiration)
+                    .chain(affiliate.endpoints.iter().map(|endpoint| endpoint.expiration))
+                    .map(|expiration| Reverse((expiration, affiliate.id.clone())))
+            })
+            .collect();
+
         TalosCluster {
             id: cluster_id,
-            affiliates: HashMap::new(),
-            watch_broadcaster: Sender::new(Self::BUFFER_SIZE),
+            bytes: affiliates.values().map(Affiliate::size).sum(),
+            affiliates,
+            watch_broadcaster: Sender::new(limits.buffer_size),
+            lag_events: Arc::new(AtomicU64::new(0)),
+            limits,
+            clock,
+            expirations,
+            grace_period: None,
+            tombstones: HashMap::new(),
         }
     }
 
-    pub async fn convert_watch_response(&self, affiliates: Vec<&Affiliate>) -> discovery_api::WatchResponse {
+    fn convert_watch_response(affiliates: Vec<Affiliate>) -> discovery_api::WatchResponse {
         discovery_api::WatchResponse {
             affiliates: affiliates
                 .into_iter()
-                .cloned()
                 .map(Affiliate::into)
                 .collect::<Vec<discovery_api::Affiliate>>(),
             deleted: false,
         }
     }
 
-    pub async fn subscribe(&self) -> Receiver<Result<WatchResponse, Status>> {
-        let mut rx = self.watch_broadcaster.subscribe();
-        let (tx, rx_stream) = mpsc::channel(Self::BUFFER_SIZE);
+    fn snapshot(&self) -> discovery_api::WatchResponse {
+        Self::convert_watch_response(self.affiliates.values().cloned().collect())
+    }
+
+    /// Returns the events resyncing a watcher which knew about the `known` affiliates: the deletion of those which are
+    /// gone, followed by a snapshot.
+    fn resync(&self, known: &HashSet<AffiliateId>) -> Vec<WatchResponse> {
+        let deleted = known
+            .iter()
+            .filter(|affiliate_id| !self.affiliates.contains_key(*affiliate_id))
+            .map(|affiliate_id| discovery_api::Affiliate {
+                id: affiliate_id.clone(),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        let mut responses = Vec::new();
+        if !deleted.is_empty() {
+            responses.push(WatchResponse {
+                affiliates: deleted,
+                deleted: true,
+            });
+        }
+        responses.push(self.snapshot());
+        responses
+    }
+
+    fn track_known(known: &mut HashSet<AffiliateId>, response: &WatchResponse) {
+        for affiliate in &response.affiliates {
+            if response.deleted {
+                known.remove(&affiliate.id);
+            } else {
+                known.insert(affiliate.id.clone());
+            }
+        }
+    }
+
+    /// Returns a stream starting with a snapshot of the cluster, followed by all changes.
+    ///
+    /// Takes the shared cluster, so the watch task can lock it again for a new snapshot when the watcher lags behind.
+    pub async fn subscribe(cluster: &SharedCluster, lag_policy: LagPolicy) -> Receiver<Result<WatchResponse, Status>> {
+        let (mut rx, watch_response, cluster_id, lag_events, buffer_size) = {
+            let cluster = cluster.lock().await;
+            (
+                cluster.watch_broadcaster.subscribe(),
+                cluster.snapshot(),
+                cluster.id.clone(),
+                cluster.lag_events.clone(),
+                cluster.limits.buffer_size,
+            )
+        };
 
-        let cluster_snapshot = self.get_affiliates().await;
-        let watch_response = self.convert_watch_response(cluster_snapshot).await;
+        // affiliates the watcher knows about, those removed while it lagged behind are deleted before a resync
+        let mut known = HashSet::new();
+        Self::track_known(&mut known, &watch_response);
+
+        let (tx, rx_stream) = mpsc::channel(buffer_size);
         let _ = tx.send(Ok(watch_response)).await.inspect_err(|err| error!("{}", err));
 
+        // a strong reference would keep the cluster from being removed by the GC
+        let cluster = Arc::downgrade(cluster);
         tokio::spawn(async move {
-            while let Ok(msg) = rx.recv().await {
-                if let Err(err) = tx.send(Ok(msg)).await {
-                    debug!("{}", err);
-                    break;
+            'watch: loop {
+                // stop as soon as the watcher disconnects, so it doesn't count as a receiver anymore
+                let recv = tokio::select! {
+                    recv = rx.recv() => recv,
+                    _ = tx.closed() => break,
+                };
+
+                let msgs = match recv {
+                    Ok(msg) => vec![Ok(msg)],
+                    Err(RecvError::Lagged(skipped)) => {
+                        lag_events.fetch_add(1, Ordering::Relaxed);
+                        warn!(
+                            "Watcher of cluster {} lagged behind by {} messages",
+                            cluster_id, skipped
+                        );
+
+                        match lag_policy {
+                            LagPolicy::Resync => {
+                                let Some(cluster) = cluster.upgrade() else {
+                                    break;
+                                };
+                                let cluster = cluster.lock().await;
+                                // the snapshot supersedes all buffered updates, so skip them
+                                rx = rx.resubscribe();
+                                cluster.resync(&known).into_iter().map(Ok).collect()
+                            }
+                            LagPolicy::Close => vec![Err(Status::aborted(format!(
+                                "watch stream lagged behind by {skipped} messages"
+                            )))],
+                        }
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                for msg in msgs {
+                    if let Ok(response) = &msg {
+                        Self::track_known(&mut known, response);
+                    }
+
+                    let close = msg.is_err();
+                    if let Err(err) = tx.send(msg).await {
+                        debug!("{}", err);
+                        break 'watch;
+                    }
+                    if close {
+                        break 'watch;
+                    }
                 }
             }
         });
@@ -92,18 +416,23 @@ impl TalosCluster {
         rx_stream
     }
 
-    pub async fn broadcast_affiliate_states(&self) {
-        let affiliate_states = self.get_affiliates().await;
-        self.send_affiliate_update(self.convert_watch_response(affiliate_states).await)
-            .await;
+    pub fn lag_events(&self) -> u64 {
+        self.lag_events.load(Ordering::Relaxed)
     }
 
-    async fn broadcast_deleted_affiliates(&self, expired: HashMap<AffiliateId, Affiliate>) {
-        if expired.is_empty() {
+    async fn broadcast_updated_affiliates(&self, updated: Vec<Affiliate>) {
+        if updated.is_empty() {
             return;
         }
-        let deleted_affiliates = expired
-            .into_values()
+        self.send_affiliate_update(Self::convert_watch_response(updated)).await;
+    }
+
+    async fn broadcast_deleted_affiliates(&self, deleted: Vec<Affiliate>) {
+        if deleted.is_empty() {
+            return;
+        }
+        let deleted_affiliates = deleted
+            .into_iter()
             .map(Affiliate::into)
             .collect::<Vec<discovery_api::Affiliate>>();
 
@@ -126,10 +455,52 @@ impl TalosCluster {
     }
 
     pub fn has_affiliates(&self) -> bool {
-        self.affiliates.is_empty()
+        !self.affiliates.is_empty()
     }
 
+    pub fn has_watchers(&self) -> bool {
+        self.watch_broadcaster.receiver_count() > 0
+    }
+
+    pub fn has_tombstones(&self) -> bool {
+        !self.tombstones.is_empty()
+    }
+
+    /// Returns the time an affiliate was deleted at, if it was deleted explicitly.
+    pub fn tombstone(&self, affiliate_id: &str) -> Option<SystemTime> {
+        self.tombstones.get(affiliate_id).copied()
+    }
+
+    pub fn tombstones(&self) -> impl Iterator<Item = (&AffiliateId, SystemTime)> {
+        self.tombstones
+            .iter()
+            .map(|(affiliate_id, deleted)| (affiliate_id, *deleted))
+    }
+
+    // later than the previous write of the affiliate, even if the system clock was stepped back in the meantime
+    pub fn write_time(&self, affiliate_id: &AffiliateId) -> SystemTime {
+        let now = self.clock.wall();
+        let previous = self
+            .affiliates
+            .get(affiliate_id)
+            .map(|affiliate| affiliate.updated)
+            .max(self.tombstone(affiliate_id));
+
+        match previous {
+            Some(previous) if previous >= now => previous + Duration::from_nanos(1),
+            _ => now,
+        }
+    }
+
+    #[cfg(test)]
     pub async fn add_affiliate(&mut self, request: &AffiliateUpdateRequest) -> Result<(), Status> {
+        let affiliate = self.prepare_affiliate(request).await?;
+        self.commit_affiliate(affiliate).await;
+        Ok(())
+    }
+
+    /// Returns the state of an affiliate with an update applied, it's only stored by `commit_affiliate`.
+    pub async fn prepare_affiliate(&self, request: &AffiliateUpdateRequest) -> Result<Affiliate, Status> {
         let ttl = request
             .ttl
             .ok_or(Status::invalid_argument("Invalid TTL"))
@@ -146,95 +517,333 @@ impl TalosCluster {
                 .inspect_err(|err| error!("{}", err.to_string()))?,
         );
 
-        let affiliate = Affiliate {
-            id: request.affiliate_id.clone(),
-            expiration: SystemTime::now() + ttl,
-            endpoints: request.affiliate_endpoints.clone(),
-            data: request.affiliate_data().to_vec(),
+        let expiration = self.clock.now() + ttl;
+        let write_time = self.write_time(&request.affiliate_id);
+        let existing = self.affiliates.get(&request.affiliate_id);
+        let previous_size = existing.map_or(0, Affiliate::size);
+
+        let mut affiliate = match existing {
+            Some(existing) => existing.clone(),
+            None if self.affiliates.len() >= self.limits.max_affiliates => {
+                return Err(Status::resource_exhausted(format!(
+                    "too many affiliates in cluster {}: maximum is {}",
+                    self.id, self.limits.max_affiliates
+                )));
+            }
+            None => Affiliate::new(request.affiliate_id.clone(), expiration),
         };
 
-        self.affiliates.insert(affiliate.id.clone(), affiliate);
+        // an absent affiliate_data field keeps the current data, while an empty one clears it
+        if let Some(data) = &request.affiliate_data {
+            affiliate.data = data.clone();
+        }
+        affiliate.expiration = expiration;
+        affiliate.updated = write_time;
+        affiliate.merge_endpoints(&request.affiliate_endpoints, expiration);
+
+        if affiliate.endpoints.len() > self.limits.max_endpoints {
+            return Err(Status::resource_exhausted(format!(
+                "too many endpoints for affiliate {}: maximum is {}",
+                affiliate.id, self.limits.max_endpoints
+            )));
+        }
+
+        let cluster_bytes = self.bytes - previous_size + affiliate.size();
+        if cluster_bytes > self.limits.max_bytes {
+            return Err(Status::resource_exhausted(format!(
+                "cluster {} exceeds its size limit: {} of maximum {} bytes",
+                self.id, cluster_bytes, self.limits.max_bytes
+            )));
+        }
+
+        Ok(affiliate)
+    }
 
-        info!("Added affiliate: {}", request.affiliate_id,);
+    pub async fn commit_affiliate(&mut self, affiliate: Affiliate) {
+        // endpoints only ever get the expiration of an update, so one entry per update covers them as well
+        self.expirations
+            .push(Reverse((affiliate.expiration, affiliate.id.clone())));
+        self.end_grace_period(&affiliate.id);
+        self.tombstones.remove(&affiliate.id);
+        self.store(affiliate.clone());
+
+        info!("Added affiliate: {}", affiliate.id);
         info!("Number of affiliates: {}", self.affiliates.len());
 
-        self.broadcast_affiliate_states().await;
+        // watchers already received the full snapshot on subscription, so only the changed affiliate is sent
+        self.broadcast_updated_affiliates(vec![affiliate]).await;
+    }
 
-        Ok(())
+    /// Stores the replicated state of an affiliate and returns the IDs of the affiliates evicted for it, or `None` if
+    /// it doesn't fit into the limits. The primary enforces the same limits, unless it's configured differently.
+    pub async fn apply_affiliate(&mut self, backup: AffiliateBackup) -> Option<Vec<AffiliateId>> {
+        let affiliate = Affiliate::restore(backup, &self.clock.time_base());
+        self.insert_affiliate(affiliate).await
     }
 
-    pub(crate) async fn get_affiliates(&self) -> Vec<&Affiliate> {
-        self.affiliates.values().collect()
+    /// Merges the state of an affiliate written on a peer and returns the IDs of the affiliates evicted for it, or
+    /// `None` if it wasn't newer than the local state or doesn't fit into the limits.
+    pub async fn merge_affiliate(&mut self, backup: AffiliateBackup) -> Option<Vec<AffiliateId>> {
+        let affiliate = Affiliate::restore(backup, &self.clock.time_base());
+        if affiliate.expiration <= self.clock.now() {
+            return None;
+        }
+        if self
+            .tombstone(&affiliate.id)
+            .is_some_and(|deleted| deleted >= affiliate.updated)
+        {
+            return None;
+        }
+        if self
+            .affiliates
+            .get(&affiliate.id)
+            .is_some_and(|existing| existing.version() >= affiliate.version())
+        {
+            return None;
+        }
+
+        let affiliate_id = affiliate.id.clone();
+        let evicted = self.insert_affiliate(affiliate).await?;
+        self.tombstones.remove(&affiliate_id);
+        Some(evicted)
     }
 
-    pub async fn get_affiliate(&self, affiliate_id: &AffiliateId) -> Option<&Affiliate> {
-        self.affiliates.get(affiliate_id)
+    /// Merges a deletion on a peer and returns whether it was newer than the local state.
+    pub async fn merge_deletion(&mut self, affiliate_id: &AffiliateId, deleted: SystemTime) -> bool {
+        if self
+            .tombstone(affiliate_id)
+            .is_some_and(|tombstone| tombstone >= deleted)
+        {
+            return false;
+        }
+        if self
+            .affiliates
+            .get(affiliate_id)
+            .is_some_and(|existing| existing.updated >= deleted)
+        {
+            return false;
+        }
+
+        // kept even if the affiliate is unknown, its older updates may still arrive from other peers
+        self.tombstones.insert(affiliate_id.clone(), deleted);
+        self.delete_affiliates(vec![affiliate_id.clone()]).await;
+        true
     }
 
-    pub async fn delete_affiliate(&mut self, affiliate_id: &AffiliateId) -> Option<Affiliate> {
-        debug!("Removing affiliate ID {} from Cluster ID {}", affiliate_id, self.id);
-        self.affiliates.remove(affiliate_id)
+    async fn insert_affiliate(&mut self, affiliate: Affiliate) -> Option<Vec<AffiliateId>> {
+        let evicted = self.evictions(&affiliate)?;
+        if !evicted.is_empty() {
+            warn!(
+                "Evicting {} affiliates of cluster {} to stay within its limits",
+                evicted.len(),
+                self.id
+            );
+            self.delete_affiliates(evicted.clone()).await;
+        }
+
+        for expiration in
+            std::iter::once(affiliate.expiration).chain(affiliate.endpoints.iter().map(|endpoint| endpoint.expiration))
+        {
+            self.expirations.push(Reverse((expiration, affiliate.id.clone())));
+        }
+
+        self.store(affiliate.clone());
+        self.broadcast_updated_affiliates(vec![affiliate]).await;
+        Some(evicted)
     }
 
-    pub async fn run_gc(&mut self) {
-        let expired = self
+    // Instances only enforce the limits on their own writes, so replicated affiliates may exceed them. The latest
+    // writes which fit are kept then, which every instance picks the same no matter in which order the writes arrive.
+    // Returns `None` if the affiliate itself doesn't fit.
+    fn evictions(&self, affiliate: &Affiliate) -> Option<Vec<AffiliateId>> {
+        if affiliate.endpoints.len() > self.limits.max_endpoints {
+            return None;
+        }
+
+        let previous = self.affiliates.get(&affiliate.id);
+        let mut count = self.affiliates.len() + usize::from(previous.is_none());
+        let mut bytes = self.bytes - previous.map_or(0, Affiliate::size) + affiliate.size();
+        let fits = |count, bytes| count <= self.limits.max_affiliates && bytes <= self.limits.max_bytes;
+        if fits(count, bytes) {
+            return Some(Vec::new());
+        }
+
+        let mut oldest = self
             .affiliates
-            .clone()
-            .into_iter()
-            .filter(|(_, a)| SystemTime::now() > a.expiration)
-            .collect::<HashMap<_, _>>();
+            .values()
+            .filter(|existing| existing.id != affiliate.id)
+            .collect::<Vec<_>>();
+        oldest.sort_unstable_by(|a, b| a.write_order().cmp(&b.write_order()));
+
+        let mut evicted = Vec::new();
+        let mut oldest = oldest.into_iter();
+        while !fits(count, bytes) {
+            let candidate = oldest
+                .next()
+                .filter(|candidate| candidate.write_order() < affiliate.write_order())?;
+            count -= 1;
+            bytes -= candidate.size();
+            evicted.push(candidate.id.clone());
+        }
+        Some(evicted)
+    }
 
-        for exp in expired.values() {
-            self.delete_affiliate(&exp.id).await;
+    // every change of the affiliates goes through here, so the size of the cluster stays accurate
+    fn store(&mut self, affiliate: Affiliate) {
+        self.bytes += affiliate.size();
+        if let Some(previous) = self.affiliates.insert(affiliate.id.clone(), affiliate) {
+            self.bytes -= previous.size();
         }
+    }
 
-        info!(
-            "GC for cluster {}: Removed {} affiliates. Remaining: {}",
-            self.id,
-            expired.len(),
-            self.affiliates.len()
-        );
+    fn remove(&mut self, affiliate_id: &AffiliateId) -> Option<Affiliate> {
+        let affiliate = self.affiliates.remove(affiliate_id)?;
+        self.bytes -= affiliate.size();
+        Some(affiliate)
+    }
+
+    /// Replaces all affiliates with replicated ones and returns the IDs of the removed affiliates.
+    pub async fn replace_affiliates(&mut self, backups: Vec<AffiliateBackup>) -> Vec<AffiliateId> {
+        let stale = {
+            let ids = backups.iter().map(|backup| &backup.id).collect::<HashSet<_>>();
+            self.affiliates
+                .keys()
+                .filter(|affiliate_id| !ids.contains(affiliate_id))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let removed = self.delete_affiliates(stale).await;
 
-        self.broadcast_deleted_affiliates(expired).await;
+        let mut removed = removed.into_iter().map(|affiliate| affiliate.id).collect::<Vec<_>>();
+        for backup in backups {
+            removed.extend(self.apply_affiliate(backup).await.unwrap_or_default());
+        }
+        removed
     }
-}
 
-impl Serialize for TalosCluster {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        let mut state = serializer.serialize_struct("TalosCluster", 2)?;
-        state.serialize_field("id", &self.id)?;
-        state.serialize_field("affiliates", &self.affiliates)?;
-        state.end()
+    pub(crate) async fn get_affiliates(&self) -> Vec<Affiliate> {
+        self.affiliates.values().cloned().collect()
+    }
+
+    pub async fn get_affiliate(&self, affiliate_id: &AffiliateId) -> Option<Affiliate> {
+        self.affiliates.get(affiliate_id).cloned()
     }
-}
 
-impl<'de> Deserialize<'de> for TalosCluster {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        #[derive(Deserialize)]
-        struct SerdeTalosCluster {
-            id: ClusterId,
-            affiliates: HashMap<AffiliateId, Affiliate>,
+    pub async fn delete_affiliate(&mut self, affiliate_id: &AffiliateId) -> Option<Affiliate> {
+        let deleted = self.write_time(affiliate_id);
+        self.delete_affiliate_at(affiliate_id, deleted).await
+    }
+
+    /// Deletes an affiliate at a time taken from `write_time` before, e.g. to persist the deletion first.
+    pub async fn delete_affiliate_at(&mut self, affiliate_id: &AffiliateId, deleted: SystemTime) -> Option<Affiliate> {
+        let affiliate = self.delete_affiliates(vec![affiliate_id.clone()]).await.pop();
+        if affiliate.is_some() {
+            self.tombstones.insert(affiliate_id.clone(), deleted);
         }
+        affiliate
+    }
 
-        let helper = SerdeTalosCluster::deserialize(deserializer)?;
+    // shared by explicit deletion and expiry, so watchers always get a deletion event
+    async fn delete_affiliates(&mut self, affiliate_ids: Vec<AffiliateId>) -> Vec<Affiliate> {
+        let deleted = affiliate_ids
+            .iter()
+            .filter_map(|affiliate_id| {
+                debug!("Removing affiliate ID {} from Cluster ID {}", affiliate_id, self.id);
+                self.remove(affiliate_id)
+            })
+            .collect::<Vec<_>>();
 
-        Ok(Self {
-            id: helper.id,
-            affiliates: helper.affiliates,
-            watch_broadcaster: Sender::new(Self::BUFFER_SIZE),
-        })
+        for affiliate in &deleted {
+            self.end_grace_period(&affiliate.id);
+        }
+        self.broadcast_deleted_affiliates(deleted.clone()).await;
+
+        deleted
+    }
+
+    /// Returns the earliest deadline at which an affiliate or endpoint may expire.
+    pub fn next_expiration(&self) -> Option<Instant> {
+        self.expirations.peek().map(|Reverse((expiration, _))| *expiration)
+    }
+
+    /// Removes affiliates and endpoints whose deadline has passed and returns the number of removed affiliates.
+    pub async fn expire(&mut self, now: Instant) -> usize {
+        let mut due = HashSet::new();
+        while let Some(Reverse((expiration, _))) = self.expirations.peek() {
+            if *expiration > now {
+                break;
+            }
+            let Reverse((_, affiliate_id)) = self.expirations.pop().unwrap();
+            due.insert(affiliate_id);
+        }
+
+        // the end of the grace period is in the heap as well, so held back affiliates are checked again
+        match &self.grace_period {
+            Some(grace_period) if now < grace_period.until => {
+                due.retain(|affiliate_id| !grace_period.pending.contains(affiliate_id))
+            }
+            Some(_) => self.grace_period = None,
+            None => (),
+        }
+
+        if due.is_empty() {
+            return 0;
+        }
+
+        let expired = {
+            let affiliates = &self.affiliates;
+            due.iter()
+                .filter(|affiliate_id| {
+                    affiliates
+                        .get(*affiliate_id)
+                        .is_some_and(|affiliate| affiliate.expiration <= now)
+                })
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let expired = self.delete_affiliates(expired).await;
+
+        let bytes = &mut self.bytes;
+        let pruned = self
+            .affiliates
+            .values_mut()
+            .filter(|affiliate| due.contains(&affiliate.id))
+            .filter_map(|affiliate| {
+                let size = affiliate.size();
+                affiliate.prune_endpoints(now).then(|| {
+                    *bytes -= size - affiliate.size();
+                    affiliate.clone()
+                })
+            })
+            .collect::<Vec<_>>();
+        self.broadcast_updated_affiliates(pruned).await;
+
+        expired.len()
+    }
+
+    pub async fn run_gc(&mut self) {
+        let expired = self.expire(self.clock.now()).await;
+
+        // older updates can't arrive anymore after the maximum TTL, they would have expired already
+        let wall = self.clock.wall();
+        let max_ttl = self.limits.max_ttl;
+        self.tombstones
+            .retain(|_, deleted| wall.duration_since(*deleted).map_or(true, |age| age < max_ttl));
+
+        info!(
+            "GC for cluster {}: Removed {} affiliates. Remaining: {}. Lagged watchers: {}",
+            self.id,
+            expired,
+            self.affiliates.len(),
+            self.lag_events()
+        );
     }
 }
 
 impl fmt::Display for TalosCluster {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let _ = write!(f, "{{ Cluster: {}", self.id);
+        let base = self.clock.time_base();
 
         for affiliate in self.affiliates.values() {
             let _ = write!(f, ", Affiliate id: {}", affiliate.id);
@@ -242,7 +851,9 @@ impl fmt::Display for TalosCluster {
             let _ = write!(
                 f,
                 ", Expiration: {}",
-                DateTime::<Utc>::from(affiliate.expiration).with_nanosecond(0).unwrap()
+                DateTime::<Utc>::from(base.wall_of(affiliate.expiration))
+                    .with_nanosecond(0)
+                    .unwrap()
             );
 
             let encrypted_data = {
@@ -261,7 +872,7 @@ impl fmt::Display for TalosCluster {
 
             for endpoint in &affiliate.endpoints {
                 let encrypted_endpoint = {
-                    let mut endpoints = &endpoint[..];
+                    let mut endpoints = &endpoint.data[..];
 
                     if endpoints.len() > 4 {
                         endpoints = &endpoints[..4];
@@ -283,3 +894,645 @@ impl fmt::Display for TalosCluster {
         write!(f, "}}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{ManualClock, SystemClock},
+        fixtures,
+    };
+    use tokio::sync::Mutex;
+
+    fn shared(cluster: TalosCluster) -> SharedCluster {
+        Arc::new(Mutex::new(cluster))
+    }
+
+    fn update_request(affiliate_data: Option<&[u8]>, affiliate_endpoints: &[&[u8]]) -> AffiliateUpdateRequest {
+        update_request_for("affiliate", affiliate_data, affiliate_endpoints)
+    }
+
+    fn update_request_for(
+        affiliate_id: &str,
+        affiliate_data: Option<&[u8]>,
+        affiliate_endpoints: &[&[u8]],
+    ) -> AffiliateUpdateRequest {
+        AffiliateUpdateRequest {
+            affiliate_id: affiliate_id.to_string(),
+            affiliate_data: affiliate_data.map(<[u8]>::to_vec),
+            affiliate_endpoints: affiliate_endpoints.iter().map(|endpoint| endpoint.to_vec()).collect(),
+            ..fixtures::update_request(60)
+        }
+    }
+
+    async fn stored_affiliate(cluster: &TalosCluster) -> discovery_api::Affiliate {
+        cluster
+            .get_affiliate(&"affiliate".to_string())
+            .await
+            .expect("affiliate exists")
+            .into()
+    }
+
+    #[tokio::test]
+    async fn absent_data_keeps_current_data() {
+        let mut cluster = TalosCluster::new("cluster".to_string(), Limits::default(), Arc::new(SystemClock));
+        cluster
+            .add_affiliate(&update_request(Some(b"data"), &[]))
+            .await
+            .unwrap();
+        cluster
+            .add_affiliate(&update_request(None, &[b"endpoint"]))
+            .await
+            .unwrap();
+
+        let affiliate = stored_affiliate(&cluster).await;
+        assert_eq!(affiliate.data, b"data");
+        assert_eq!(affiliate.endpoints, vec![b"endpoint".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn empty_data_clears_current_data() {
+        let mut cluster = TalosCluster::new("cluster".to_string(), Limits::default(), Arc::new(SystemClock));
+        cluster
+            .add_affiliate(&update_request(Some(b"data"), &[]))
+            .await
+            .unwrap();
+        cluster.add_affiliate(&update_request(Some(b""), &[])).await.unwrap();
+
+        assert!(stored_affiliate(&cluster).await.data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn absent_endpoints_keep_current_endpoints() {
+        let mut cluster = TalosCluster::new("cluster".to_string(), Limits::default(), Arc::new(SystemClock));
+        cluster
+            .add_affiliate(&update_request(Some(b"data"), &[b"endpoint1"]))
+            .await
+            .unwrap();
+        cluster
+            .add_affiliate(&update_request(Some(b"new data"), &[]))
+            .await
+            .unwrap();
+        cluster
+            .add_affiliate(&update_request(None, &[b"endpoint1", b"endpoint2"]))
+            .await
+            .unwrap();
+
+        let affiliate = stored_affiliate(&cluster).await;
+        assert_eq!(affiliate.data, b"new data");
+        assert_eq!(affiliate.endpoints, vec![b"endpoint1".to_vec(), b"endpoint2".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn watchers_receive_snapshot_then_deltas() {
+        let cluster = shared(TalosCluster::new(
+            "cluster".to_string(),
+            Limits::default(),
+            Arc::new(SystemClock),
+        ));
+        cluster
+            .lock()
+            .await
+            .add_affiliate(&update_request_for("first", Some(b"first"), &[]))
+            .await
+            .unwrap();
+
+        let mut watcher = TalosCluster::subscribe(&cluster, LagPolicy::Resync).await;
+        let snapshot = watcher.recv().await.unwrap().unwrap();
+        assert_eq!(snapshot.affiliates.len(), 1);
+        assert!(!snapshot.deleted);
+
+        cluster
+            .lock()
+            .await
+            .add_affiliate(&update_request_for("second", Some(b"second"), &[]))
+            .await
+            .unwrap();
+
+        let delta = watcher.recv().await.unwrap().unwrap();
+        assert_eq!(delta.affiliates.len(), 1);
+        assert_eq!(delta.affiliates[0].id, "second");
+        assert!(!delta.deleted);
+    }
+
+    #[tokio::test]
+    async fn deleting_affiliate_emits_deletion_event() {
+        let mut cluster = TalosCluster::new("cluster".to_string(), Limits::default(), Arc::new(SystemClock));
+        cluster
+            .add_affiliate(&update_request_for("first", Some(b"first"), &[]))
+            .await
+            .unwrap();
+        cluster
+            .add_affiliate(&update_request_for("second", Some(b"second"), &[]))
+            .await
+            .unwrap();
+
+        let cluster = shared(cluster);
+        let mut watcher = TalosCluster::subscribe(&cluster, LagPolicy::Resync).await;
+        watcher.recv().await.unwrap().unwrap();
+
+        assert!(cluster
+            .lock()
+            .await
+            .delete_affiliate(&"first".to_string())
+            .await
+            .is_some());
+
+        let event = watcher.recv().await.unwrap().unwrap();
+        assert!(event.deleted);
+        assert_eq!(event.affiliates.len(), 1);
+        assert_eq!(event.affiliates[0].id, "first");
+    }
+
+    #[tokio::test]
+    async fn affiliates_expire_at_their_deadline() {
+        let clock = ManualClock::new();
+        let mut cluster = TalosCluster::new("cluster".to_string(), Limits::default(), clock.clone());
+        cluster
+            .add_affiliate(&update_request(Some(b"data"), &[]))
+            .await
+            .unwrap();
+        assert_eq!(cluster.next_expiration(), Some(clock.now() + Duration::from_secs(60)));
+
+        let cluster = shared(cluster);
+        let mut watcher = TalosCluster::subscribe(&cluster, LagPolicy::Resync).await;
+        watcher.recv().await.unwrap().unwrap();
+        let mut cluster = cluster.lock().await;
+
+        clock.advance(Duration::from_secs(59));
+        cluster.run_gc().await;
+        assert!(watcher.try_recv().is_err());
+        assert!(cluster.has_affiliates());
+
+        clock.advance(Duration::from_secs(1));
+        cluster.run_gc().await;
+        let event = watcher.recv().await.unwrap().unwrap();
+        assert!(event.deleted);
+        assert_eq!(event.affiliates[0].id, "affiliate");
+        assert!(cluster.next_expiration().is_none());
+    }
+
+    #[tokio::test]
+    async fn refreshed_affiliate_only_loses_stale_endpoints() {
+        let clock = ManualClock::new();
+        let mut cluster = TalosCluster::new("cluster".to_string(), Limits::default(), clock.clone());
+        cluster
+            .add_affiliate(&update_request(Some(b"data"), &[b"endpoint1"]))
+            .await
+            .unwrap();
+
+        clock.advance(Duration::from_secs(30));
+        cluster
+            .add_affiliate(&update_request(None, &[b"endpoint2"]))
+            .await
+            .unwrap();
+
+        let cluster = shared(cluster);
+        let mut watcher = TalosCluster::subscribe(&cluster, LagPolicy::Resync).await;
+        watcher.recv().await.unwrap().unwrap();
+        let mut cluster = cluster.lock().await;
+
+        clock.advance(Duration::from_secs(30));
+        cluster.run_gc().await;
+        let event = watcher.recv().await.unwrap().unwrap();
+        assert!(!event.deleted);
+        assert_eq!(event.affiliates[0].endpoints, vec![b"endpoint2".to_vec()]);
+        assert_eq!(cluster.next_expiration(), Some(clock.now() + Duration::from_secs(30)));
+
+        clock.advance(Duration::from_secs(30));
+        cluster.run_gc().await;
+        assert!(watcher.recv().await.unwrap().unwrap().deleted);
+    }
+
+    #[tokio::test]
+    async fn restore_accounts_for_time_since_backup() {
+        let clock = ManualClock::new();
+        let mut cluster = TalosCluster::new("cluster".to_string(), Limits::default(), clock.clone());
+        cluster
+            .add_affiliate(&update_request(Some(b"data"), &[b"endpoint"]))
+            .await
+            .unwrap();
+
+        let base = clock.time_base();
+        let backup = cluster.backup(&base);
+
+        clock.advance(Duration::from_secs(20));
+        let base = clock.time_base().restore(base.wall, Limits::default().max_ttl);
+        let restored = TalosCluster::from_backup(backup, &base, Limits::default(), clock.clone());
+        assert_eq!(restored.next_expiration(), Some(clock.now() + Duration::from_secs(40)));
+    }
+
+    #[tokio::test]
+    async fn replicated_affiliates_replace_stale_ones() {
+        let clock = ManualClock::new();
+        let mut primary = TalosCluster::new("cluster".to_string(), Limits::default(), clock.clone());
+        primary
+            .add_affiliate(&update_request_for("kept", Some(b"kept"), &[b"endpoint"]))
+            .await
+            .unwrap();
+        let mut standby = TalosCluster::new("cluster".to_string(), Limits::default(), clock.clone());
+        standby
+            .add_affiliate(&update_request_for("stale", Some(b"stale"), &[]))
+            .await
+            .unwrap();
+
+        let standby = shared(standby);
+        let mut watcher = TalosCluster::subscribe(&standby, LagPolicy::Resync).await;
+        watcher.recv().await.unwrap().unwrap();
+        let mut standby = standby.lock().await;
+
+        let backup = primary.backup(&clock.time_base());
+        assert_eq!(
+            standby.replace_affiliates(backup.affiliates).await,
+            vec!["stale".to_string()]
+        );
+
+        let deletion = watcher.recv().await.unwrap().unwrap();
+        assert!(deletion.deleted);
+        assert_eq!(deletion.affiliates[0].id, "stale");
+        let update = watcher.recv().await.unwrap().unwrap();
+        assert_eq!(update.affiliates[0].id, "kept");
+
+        assert_eq!(standby.next_expiration(), Some(clock.now() + Duration::from_secs(60)));
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(standby.expire(clock.now()).await, 1);
+    }
+
+    fn stored_data(cluster: &TalosCluster) -> Vec<u8> {
+        cluster
+            .affiliate_backup(&"affiliate".to_string())
+            .expect("affiliate exists")
+            .data
+    }
+
+    #[tokio::test]
+    async fn merges_keep_the_latest_write() {
+        let clock = ManualClock::new();
+        let mut first = TalosCluster::new("cluster".to_string(), Limits::default(), clock.clone());
+        let mut second = TalosCluster::new("cluster".to_string(), Limits::default(), clock.clone());
+
+        first.add_affiliate(&update_request(Some(b"older"), &[])).await.unwrap();
+        let older = first.affiliate_backup(&"affiliate".to_string()).unwrap();
+        clock.advance(Duration::from_secs(1));
+        second
+            .add_affiliate(&update_request(Some(b"latest"), &[]))
+            .await
+            .unwrap();
+        let latest = second.affiliate_backup(&"affiliate".to_string()).unwrap();
+
+        assert!(first.merge_affiliate(latest.clone()).await.is_some());
+        assert!(first.merge_affiliate(latest).await.is_none());
+        assert!(second.merge_affiliate(older).await.is_none());
+        assert_eq!(stored_data(&first), b"latest");
+        assert_eq!(stored_data(&second), b"latest");
+    }
+
+    #[tokio::test]
+    async fn full_peers_converge_on_the_latest_writes() {
+        let clock = ManualClock::new();
+        let limits = Limits {
+            max_affiliates: 3,
+            ..Default::default()
+        };
+        let mut first = TalosCluster::new("cluster".to_string(), limits, clock.clone());
+        let mut second = TalosCluster::new("cluster".to_string(), limits, clock.clone());
+
+        // both peers fill the cluster on their own, with interleaved writes
+        for (cluster, affiliate_ids) in [(&mut first, ["a", "c", "e"]), (&mut second, ["b", "d", "f"])] {
+            for affiliate_id in affiliate_ids {
+                cluster
+                    .add_affiliate(&update_request_for(affiliate_id, Some(b"data"), &[]))
+                    .await
+                    .unwrap();
+            }
+        }
+        let first_backups = first.backup(&clock.time_base()).affiliates;
+        let second_backups = second.backup(&clock.time_base()).affiliates;
+
+        // the writes happen at the same time, so the order of their IDs decides
+        for backup in second_backups {
+            first.merge_affiliate(backup).await;
+        }
+        for backup in first_backups.into_iter().rev() {
+            second.merge_affiliate(backup).await;
+        }
+
+        for cluster in [&first, &second] {
+            let mut affiliate_ids = cluster.affiliates.keys().cloned().collect::<Vec<_>>();
+            affiliate_ids.sort();
+            assert_eq!(affiliate_ids, ["d", "e", "f"]);
+            assert_eq!(cluster.bytes, 3 * (1 + b"data".len()));
+        }
+
+        // a later write displaces the oldest one everywhere, an older one is rejected
+        clock.advance(Duration::from_secs(1));
+        let mut third = TalosCluster::new("cluster".to_string(), Limits::default(), clock.clone());
+        third
+            .add_affiliate(&update_request_for("a", Some(b"data"), &[]))
+            .await
+            .unwrap();
+        let latest = third.affiliate_backup(&"a".to_string()).unwrap();
+        assert_eq!(first.merge_affiliate(latest.clone()).await, Some(vec!["d".to_string()]));
+        assert_eq!(second.merge_affiliate(latest).await, Some(vec!["d".to_string()]));
+
+        let mut older = second.affiliate_backup(&"e".to_string()).unwrap();
+        older.id = "b".to_string();
+        assert_eq!(first.merge_affiliate(older).await, None);
+        assert!(!first.affiliates.contains_key("b"));
+    }
+
+    #[tokio::test]
+    async fn deletions_win_over_older_writes() {
+        let clock = ManualClock::new();
+        let mut first = TalosCluster::new("cluster".to_string(), Limits::default(), clock.clone());
+        let mut second = TalosCluster::new("cluster".to_string(), Limits::default(), clock.clone());
+
+        first.add_affiliate(&update_request(Some(b"data"), &[])).await.unwrap();
+        let written = first.affiliate_backup(&"affiliate".to_string()).unwrap();
+        clock.advance(Duration::from_secs(1));
+        first.delete_affiliate(&"affiliate".to_string()).await;
+        let deleted = first.tombstone("affiliate").unwrap();
+
+        // the deletion arrives before the write it deleted
+        assert!(second.merge_deletion(&"affiliate".to_string(), deleted).await);
+        assert!(second.merge_affiliate(written).await.is_none());
+        assert!(!second.has_affiliates());
+
+        clock.advance(Duration::from_secs(1));
+        first.add_affiliate(&update_request(Some(b"again"), &[])).await.unwrap();
+        assert!(second
+            .merge_affiliate(first.affiliate_backup(&"affiliate".to_string()).unwrap())
+            .await
+            .is_some());
+        assert_eq!(stored_data(&second), b"again");
+        assert!(!second.has_tombstones());
+
+        first.delete_affiliate(&"affiliate".to_string()).await;
+        clock.advance(Limits::default().max_ttl + Duration::from_secs(1));
+        first.run_gc().await;
+        assert!(!first.has_tombstones());
+    }
+
+    async fn restore_after_downtime(clock: &Arc<ManualClock>, affiliate_ids: &[&str]) -> TalosCluster {
+        let mut cluster = TalosCluster::new("cluster".to_string(), Limits::default(), clock.clone());
+        for affiliate_id in affiliate_ids {
+            cluster
+                .add_affiliate(&update_request_for(affiliate_id, Some(b"data"), &[b"endpoint"]))
+                .await
+                .unwrap();
+        }
+        let base = clock.time_base();
+        let backup = cluster.backup(&base);
+
+        clock.advance(Duration::from_secs(120));
+        let base = clock.time_base().restore(base.wall, Limits::default().max_ttl);
+        let mut restored = TalosCluster::from_backup(backup, &base, Limits::default(), clock.clone());
+        restored.start_grace_period(Duration::from_secs(60));
+        restored
+    }
+
+    #[tokio::test]
+    async fn restored_affiliates_are_kept_during_grace_period() {
+        let clock = ManualClock::new();
+        let mut cluster = restore_after_downtime(&clock, &["affiliate"]).await;
+
+        cluster.run_gc().await;
+        assert_eq!(stored_affiliate(&cluster).await.endpoints, vec![b"endpoint".to_vec()]);
+
+        clock.advance(Duration::from_secs(59));
+        cluster.run_gc().await;
+        assert!(cluster.has_affiliates());
+
+        clock.advance(Duration::from_secs(1));
+        cluster.run_gc().await;
+        assert!(!cluster.has_affiliates());
+    }
+
+    #[tokio::test]
+    async fn grace_period_ends_once_affiliates_reregistered() {
+        let clock = ManualClock::new();
+        let mut cluster = restore_after_downtime(&clock, &["first", "second"]).await;
+
+        let mut request = update_request_for("first", None, &[]);
+        request.ttl = Some(prost_types::Duration { seconds: 10, nanos: 0 });
+        cluster.add_affiliate(&request).await.unwrap();
+
+        // the second affiliate is still held back
+        clock.advance(Duration::from_secs(10));
+        cluster.run_gc().await;
+        assert!(cluster.get_affiliate(&"first".to_string()).await.is_none());
+        assert!(cluster.get_affiliate(&"second".to_string()).await.is_some());
+
+        request.affiliate_id = "second".to_string();
+        cluster.add_affiliate(&request).await.unwrap();
+        clock.advance(Duration::from_secs(10));
+        cluster.run_gc().await;
+        assert!(!cluster.has_affiliates());
+    }
+
+    async fn lag_behind(cluster: &mut TalosCluster) {
+        for i in 0..Limits::default().buffer_size * 3 {
+            cluster
+                .add_affiliate(&update_request_for(&i.to_string(), Some(b"data"), &[]))
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn lagging_watcher_is_resynced() {
+        let cluster = shared(TalosCluster::new(
+            "cluster".to_string(),
+            Limits::default(),
+            Arc::new(SystemClock),
+        ));
+        let mut watcher = TalosCluster::subscribe(&cluster, LagPolicy::Resync).await;
+        assert!(watcher.recv().await.unwrap().unwrap().affiliates.is_empty());
+
+        lag_behind(&mut *cluster.lock().await).await;
+
+        let snapshot = watcher.recv().await.unwrap().unwrap();
+        assert_eq!(snapshot.affiliates.len(), Limits::default().buffer_size * 3);
+        assert_eq!(cluster.lock().await.lag_events(), 1);
+
+        cluster
+            .lock()
+            .await
+            .add_affiliate(&update_request_for("after lag", Some(b"data"), &[]))
+            .await
+            .unwrap();
+        let delta = watcher.recv().await.unwrap().unwrap();
+        assert_eq!(delta.affiliates[0].id, "after lag");
+    }
+
+    #[tokio::test]
+    async fn lagging_watcher_is_told_about_deletions() {
+        let cluster = shared(TalosCluster::new(
+            "cluster".to_string(),
+            Limits::default(),
+            Arc::new(SystemClock),
+        ));
+        cluster
+            .lock()
+            .await
+            .add_affiliate(&update_request_for("deleted", Some(b"data"), &[]))
+            .await
+            .unwrap();
+        let mut watcher = TalosCluster::subscribe(&cluster, LagPolicy::Resync).await;
+        assert_eq!(watcher.recv().await.unwrap().unwrap().affiliates.len(), 1);
+
+        {
+            let mut cluster = cluster.lock().await;
+            cluster.delete_affiliate(&"deleted".to_string()).await.unwrap();
+            lag_behind(&mut cluster).await;
+        }
+
+        let deletion = watcher.recv().await.unwrap().unwrap();
+        assert!(deletion.deleted);
+        assert_eq!(deletion.affiliates.len(), 1);
+        assert_eq!(deletion.affiliates[0].id, "deleted");
+
+        let snapshot = watcher.recv().await.unwrap().unwrap();
+        assert!(!snapshot.deleted);
+        assert_eq!(snapshot.affiliates.len(), Limits::default().buffer_size * 3);
+    }
+
+    #[tokio::test]
+    async fn lagging_watcher_is_closed() {
+        let cluster = shared(TalosCluster::new(
+            "cluster".to_string(),
+            Limits::default(),
+            Arc::new(SystemClock),
+        ));
+        let mut watcher = TalosCluster::subscribe(&cluster, LagPolicy::Close).await;
+        watcher.recv().await.unwrap().unwrap();
+        let mut cluster = cluster.lock().await;
+
+        lag_behind(&mut cluster).await;
+
+        let status = watcher.recv().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), discovery_api::tonic::Code::Aborted);
+        assert!(watcher.recv().await.is_none());
+        assert_eq!(cluster.lag_events(), 1);
+    }
+
+    #[tokio::test]
+    async fn disconnected_watcher_is_released() {
+        let cluster = shared(TalosCluster::new(
+            "cluster".to_string(),
+            Limits::default(),
+            Arc::new(SystemClock),
+        ));
+        let watcher = TalosCluster::subscribe(&cluster, LagPolicy::Resync).await;
+        assert!(cluster.lock().await.has_watchers());
+
+        drop(watcher);
+        while cluster.lock().await.has_watchers() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn affiliate_limit_is_enforced() {
+        let limits = Limits {
+            max_affiliates: 1,
+            ..Default::default()
+        };
+        let mut cluster = TalosCluster::new("cluster".to_string(), limits, Arc::new(SystemClock));
+        cluster
+            .add_affiliate(&update_request_for("first", Some(b"data"), &[]))
+            .await
+            .unwrap();
+        cluster
+            .add_affiliate(&update_request_for("first", Some(b"new data"), &[]))
+            .await
+            .unwrap();
+
+        let status = cluster
+            .add_affiliate(&update_request_for("second", Some(b"data"), &[]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), discovery_api::tonic::Code::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn endpoint_limit_is_enforced() {
+        let limits = Limits {
+            max_endpoints: 2,
+            ..Default::default()
+        };
+        let mut cluster = TalosCluster::new("cluster".to_string(), limits, Arc::new(SystemClock));
+        cluster
+            .add_affiliate(&update_request(None, &[b"endpoint1", b"endpoint2"]))
+            .await
+            .unwrap();
+
+        let status = cluster
+            .add_affiliate(&update_request(None, &[b"endpoint2", b"endpoint3"]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), discovery_api::tonic::Code::ResourceExhausted);
+        assert_eq!(stored_affiliate(&cluster).await.endpoints.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn cluster_size_limit_is_enforced() {
+        let limits = Limits {
+            max_bytes: 32,
+            ..Default::default()
+        };
+        let mut cluster = TalosCluster::new("cluster".to_string(), limits, Arc::new(SystemClock));
+        cluster
+            .add_affiliate(&update_request_for("first", Some(&[0; 16]), &[]))
+            .await
+            .unwrap();
+
+        let status = cluster
+            .add_affiliate(&update_request_for("second", Some(&[0; 16]), &[]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), discovery_api::tonic::Code::ResourceExhausted);
+        assert!(cluster.get_affiliate(&"second".to_string()).await.is_none());
+
+        // the size of removed affiliates is released again
+        cluster.delete_affiliate(&"first".to_string()).await;
+        cluster
+            .add_affiliate(&update_request_for("second", Some(&[0; 16]), &[]))
+            .await
+            .unwrap();
+        assert_eq!(cluster.bytes, "second".len() + 16);
+    }
+
+    fn endpoint_data(affiliate: &Affiliate) -> Vec<Vec<u8>> {
+        affiliate
+            .endpoints
+            .iter()
+            .map(|endpoint| endpoint.data.clone())
+            .collect()
+    }
+
+    #[test]
+    fn merged_endpoints_keep_their_latest_expiration() {
+        let now = Instant::now();
+        let mut affiliate = Affiliate::new("affiliate".to_string(), now);
+
+        affiliate.merge_endpoints(&[vec![1], vec![2]], now + Duration::from_secs(60));
+        affiliate.merge_endpoints(&[vec![2], vec![3]], now + Duration::from_secs(30));
+
+        assert_eq!(endpoint_data(&affiliate), vec![vec![1], vec![2], vec![3]]);
+        assert_eq!(affiliate.endpoints[1].expiration, now + Duration::from_secs(60));
+        assert_eq!(affiliate.endpoints[2].expiration, now + Duration::from_secs(30));
+    }
+
+    #[test]
+    fn only_expired_endpoints_are_pruned() {
+        let now = Instant::now();
+        let mut affiliate = Affiliate::new("affiliate".to_string(), now);
+        affiliate.merge_endpoints(&[vec![1]], now + Duration::from_secs(30));
+        affiliate.merge_endpoints(&[vec![2]], now + Duration::from_secs(60));
+
+        assert!(!affiliate.prune_endpoints(now + Duration::from_secs(29)));
+        assert!(affiliate.prune_endpoints(now + Duration::from_secs(30)));
+        assert_eq!(endpoint_data(&affiliate), vec![vec![2]]);
+    }
+}
@@ -0,0 +1,162 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{anyhow, bail, ensure, Context};
+use std::{fmt, path::Path, str::FromStr};
+
+// Encrypted backup format:
+//
+//   magic "TDSE" | version: u8 | nonce: 12 bytes | AES-256-GCM ciphertext and tag
+//
+// The magic and version are authenticated as associated data.
+const MAGIC: &[u8; 4] = b"TDSE";
+const VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 1 + NONCE_LEN;
+
+pub(crate) fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(MAGIC)
+}
+
+/// Keys encrypting the backups.
+///
+/// The first key encrypts new backups, all of them are tried to decrypt, so a key can be rotated by prepending the new
+/// one and dropping the old one once a backup has been written with the new key.
+#[derive(Clone)]
+pub struct BackupKeys {
+    keys: Vec<Key<Aes256Gcm>>,
+}
+
+// never leaks the keys into the logs
+impl fmt::Debug for BackupKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BackupKeys({} redacted)", self.keys.len())
+    }
+}
+
+impl FromStr for BackupKeys {
+    type Err = anyhow::Error;
+
+    // hex encoded 256-bit keys, separated by commas or whitespace
+    fn from_str(keys: &str) -> Result<Self, Self::Err> {
+        let keys = keys
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|key| !key.is_empty())
+            .enumerate()
+            .map(|(i, key)| {
+                let key = hex::decode(key).map_err(|_| anyhow!("backup key {} is not hex encoded", i + 1))?;
+                ensure!(key.len() == 32, "backup key {} is not 256 bits long", i + 1);
+                Ok(*Key::<Aes256Gcm>::from_slice(&key))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        ensure!(!keys.is_empty(), "no backup key given");
+
+        Ok(Self { keys })
+    }
+}
+
+impl BackupKeys {
+    /// Reads the keys from a file, one per line, lines starting with `#` are ignored.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("couldn't read backup key file {}", path.display()))?;
+        contents
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .collect::<Vec<_>>()
+            .join("\n")
+            .parse()
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Aes256Gcm::new(&self.keys[0])
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &header,
+                },
+            )
+            .map_err(|_| anyhow!("couldn't encrypt backup"))?;
+
+        let mut contents = header;
+        contents.extend_from_slice(&nonce);
+        contents.extend_from_slice(&ciphertext);
+        Ok(contents)
+    }
+
+    pub fn decrypt(&self, contents: &[u8]) -> anyhow::Result<Vec<u8>> {
+        ensure!(
+            contents.len() >= HEADER_LEN && is_encrypted(contents),
+            "backup is not encrypted"
+        );
+        let version = contents[MAGIC.len()];
+        ensure!(version == VERSION, "unsupported encrypted backup version {version}");
+
+        let (header, rest) = contents.split_at(MAGIC.len() + 1);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        for key in &self.keys {
+            let payload = Payload {
+                msg: ciphertext,
+                aad: header,
+            };
+            if let Ok(plaintext) = Aes256Gcm::new(key).decrypt(Nonce::from_slice(nonce), payload) {
+                return Ok(plaintext);
+            }
+        }
+
+        bail!(
+            "backup can't be decrypted with any of the {} configured backup keys, the key is wrong or the backup is \
+             corrupted",
+            self.keys.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD_KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const NEW_KEY: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    #[test]
+    fn rotated_keys_decrypt_old_backups() {
+        let old: BackupKeys = OLD_KEY.parse().unwrap();
+        let rotated: BackupKeys = format!("{NEW_KEY},{OLD_KEY}").parse().unwrap();
+
+        let contents = old.encrypt(b"backup").unwrap();
+        assert!(is_encrypted(&contents));
+        assert_eq!(rotated.decrypt(&contents).unwrap(), b"backup");
+
+        let contents = rotated.encrypt(b"backup").unwrap();
+        assert_eq!(rotated.decrypt(&contents).unwrap(), b"backup");
+        assert!(old.decrypt(&contents).unwrap_err().to_string().contains("key is wrong"));
+    }
+
+    #[test]
+    fn tampered_backups_are_rejected() {
+        let keys: BackupKeys = OLD_KEY.parse().unwrap();
+        let mut contents = keys.encrypt(b"backup").unwrap();
+
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        assert!(keys.decrypt(&contents).is_err());
+        assert!(keys.decrypt(b"backup").is_err());
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected_without_leaking_them() {
+        assert!("".parse::<BackupKeys>().is_err());
+        assert!("0011".parse::<BackupKeys>().is_err());
+        assert!(format!("{OLD_KEY},xyz").parse::<BackupKeys>().is_err());
+
+        let keys: BackupKeys = format!("{OLD_KEY}\n{NEW_KEY}").parse().unwrap();
+        assert_eq!(format!("{keys:?}"), "BackupKeys(2 redacted)");
+    }
+}
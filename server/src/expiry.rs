@@ -0,0 +1,144 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::{Arc, Mutex},
+};
+use tokio::{sync::Notify, time::Instant};
+
+use crate::{clock::Clock, cluster::ClusterId};
+
+#[derive(Default)]
+struct Deadlines {
+    queue: BinaryHeap<Reverse<(Instant, ClusterId)>>,
+    // earliest scheduled deadline per cluster, queue entries not matching it are stale
+    scheduled: HashMap<ClusterId, Instant>,
+}
+
+/// Deadlines at which clusters have affiliates or endpoints to expire.
+///
+/// Every cluster is queued at most once with its earliest deadline. Clusters are rescheduled by their owner after
+/// they have been expired.
+pub(crate) struct ExpiryQueue {
+    deadlines: Mutex<Deadlines>,
+    notify: Notify,
+    clock: Arc<dyn Clock>,
+}
+
+impl ExpiryQueue {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            deadlines: Mutex::default(),
+            notify: Notify::new(),
+            clock,
+        }
+    }
+
+    pub fn schedule(&self, cluster_id: &ClusterId, deadline: Instant) {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        if deadlines
+            .scheduled
+            .get(cluster_id)
+            .is_some_and(|scheduled| *scheduled <= deadline)
+        {
+            return;
+        }
+
+        let earliest = deadlines.queue.peek().is_none_or(|Reverse((next, _))| deadline < *next);
+
+        deadlines.scheduled.insert(cluster_id.clone(), deadline);
+        deadlines.queue.push(Reverse((deadline, cluster_id.clone())));
+
+        // wake up the waiter, which might sleep until a later deadline
+        if earliest {
+            self.notify.notify_one();
+        }
+    }
+
+    /// Waits until the earliest deadline has passed and returns the clusters which are due.
+    pub async fn next_due(&self) -> Vec<ClusterId> {
+        loop {
+            let next = self
+                .deadlines
+                .lock()
+                .unwrap()
+                .queue
+                .peek()
+                .map(|Reverse((next, _))| *next);
+
+            match next {
+                None => self.notify.notified().await,
+                Some(deadline) if deadline > self.clock.now() => {
+                    tokio::select! {
+                        _ = self.clock.sleep_until(deadline) => (),
+                        _ = self.notify.notified() => (),
+                    }
+                }
+                Some(_) => return self.pop_due(self.clock.now()),
+            }
+        }
+    }
+
+    fn pop_due(&self, now: Instant) -> Vec<ClusterId> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        let mut due = Vec::new();
+
+        while let Some(Reverse((deadline, _))) = deadlines.queue.peek() {
+            if *deadline > now {
+                break;
+            }
+
+            let Reverse((deadline, cluster_id)) = deadlines.queue.pop().unwrap();
+            if deadlines.scheduled.get(&cluster_id) == Some(&deadline) {
+                deadlines.scheduled.remove(&cluster_id);
+                due.push(cluster_id);
+            }
+        }
+
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn clusters_are_due_once_at_their_earliest_deadline() {
+        let clock = ManualClock::new();
+        let queue = ExpiryQueue::new(clock.clone());
+        let now = clock.now();
+        let cluster = "cluster".to_string();
+
+        queue.schedule(&cluster, now + Duration::from_secs(60));
+        queue.schedule(&cluster, now + Duration::from_secs(10));
+        queue.schedule(&cluster, now + Duration::from_secs(30));
+        queue.schedule(&"later".to_string(), now + Duration::from_secs(60));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(queue.next_due().await, vec![cluster.clone()]);
+        assert!(queue.pop_due(now + Duration::from_secs(59)).is_empty());
+        assert_eq!(queue.pop_due(now + Duration::from_secs(60)), vec!["later".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn earlier_deadline_wakes_up_waiter() {
+        let clock = ManualClock::new();
+        let queue = Arc::new(ExpiryQueue::new(clock.clone()));
+        let now = clock.now();
+
+        queue.schedule(&"later".to_string(), now + Duration::from_secs(60));
+        let waiter = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.next_due().await }
+        });
+        clock.sleeping_until(now + Duration::from_secs(60)).await;
+
+        queue.schedule(&"earlier".to_string(), now + Duration::from_secs(10));
+        clock.sleeping_until(now + Duration::from_secs(10)).await;
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(waiter.await.unwrap(), vec!["earlier".to_string()]);
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::bail;
+use discovery_api::tonic::async_trait;
+use std::{sync::Arc, time::SystemTime};
+use tracing::{error, info};
+
+use crate::{
+    backup::BackupFiles,
+    clock::{Clock, Interval},
+    cluster::ClusterId,
+    encryption::{self, BackupKeys},
+    journal::{self, Change, Journal, JournalEntry, JournalSync},
+    service::ServiceOptions,
+    snapshot::{self, AffiliateBackup, Snapshot},
+    state::StateStore,
+    store::ClusterStore,
+};
+
+/// Store writing snapshots to the backup path, and optionally a journal of the changes between them.
+///
+/// Old JSON backups and snapshots of the upstream discovery service are restored as well if there is no newer
+/// snapshot.
+pub(crate) struct FileStore {
+    backup_files: Option<BackupFiles>,
+    legacy_backup_files: Option<BackupFiles>,
+    upstream_snapshot_files: Option<BackupFiles>,
+    backup_keys: Option<BackupKeys>,
+    journal: Option<Arc<Journal>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl FileStore {
+    pub const BACKUP_FILE_NAME: &str = "discovery_service_backup.bin";
+    // backups used to be JSON documents, they are still restored if there is no newer backup
+    pub const LEGACY_BACKUP_FILE_NAME: &str = "discovery_service_backup.json";
+    pub const JOURNAL_FILE_NAME: &str = "discovery_service_journal";
+
+    pub async fn open(options: &ServiceOptions, clock: Arc<dyn Clock>) -> anyhow::Result<Self> {
+        let backup_files = options
+            .backup_path
+            .as_ref()
+            .map(|path| BackupFiles::new(path.join(Self::BACKUP_FILE_NAME), options.backup_generations));
+        let legacy_backup_files = options
+            .backup_path
+            .as_ref()
+            .map(|path| BackupFiles::new(path.join(Self::LEGACY_BACKUP_FILE_NAME), options.backup_generations));
+        // the upstream service only reads a single file
+        let upstream_snapshot_files = options
+            .upstream_snapshot_path
+            .clone()
+            .map(|path| BackupFiles::new(path, 1));
+
+        let journal = match (options.journal_sync, &options.backup_path) {
+            // the journal is kept next to the backups, without them there is nothing to replay it on
+            (JournalSync::Off, _) | (_, None) => None,
+            (sync, Some(path)) => Some(Arc::new(
+                Journal::open(path.join(Self::JOURNAL_FILE_NAME), options.backup_keys.clone(), sync).await?,
+            )),
+        };
+
+        if let Some(journal) = journal.as_ref().filter(|journal| journal.sync == JournalSync::Interval) {
+            tokio::task::spawn(Self::run_journal_sync_loop(
+                journal.clone(),
+                Interval::new(clock.clone(), options.journal_sync_interval),
+            ));
+        }
+
+        Ok(Self {
+            backup_files,
+            legacy_backup_files,
+            upstream_snapshot_files,
+            backup_keys: options.backup_keys.clone(),
+            journal,
+            clock,
+        })
+    }
+
+    async fn run_journal_sync_loop(journal: Arc<Journal>, mut interval: Interval) {
+        info!("Journal sync loop started");
+        loop {
+            interval.tick().await;
+            if let Err(err) = journal.sync().await {
+                error!("couldn't sync journal: {}", err);
+            }
+        }
+    }
+
+    fn decode_backup(&self, contents: &[u8]) -> anyhow::Result<Snapshot> {
+        if !encryption::is_encrypted(contents) {
+            if self.backup_keys.is_some() {
+                info!("Restoring unencrypted backup, the next backup is encrypted");
+            }
+            return snapshot::decode(contents);
+        }
+
+        let Some(keys) = &self.backup_keys else {
+            bail!("backup is encrypted, but no backup key is configured");
+        };
+        snapshot::decode(&keys.decrypt(contents)?)
+    }
+
+    // own backups take precedence over an upstream snapshot, which is only imported when migrating
+    async fn load_snapshot(&self) -> anyhow::Result<Option<Snapshot>> {
+        if let Some(backup_files) = &self.backup_files {
+            if let Some(snapshot) = backup_files.load(|contents| self.decode_backup(contents)).await? {
+                return Ok(Some(snapshot));
+            }
+        }
+
+        if let Some(legacy_backup_files) = &self.legacy_backup_files {
+            if let Some(snapshot) = legacy_backup_files.load(snapshot::decode_json).await? {
+                info!("Migrating JSON backup, the next backup is written in the binary format");
+                return Ok(Some(snapshot));
+            }
+        }
+
+        if let Some(upstream_snapshot_files) = &self.upstream_snapshot_files {
+            if let Some(snapshot) = upstream_snapshot_files.load(snapshot::decode_upstream).await? {
+                info!("Importing state snapshot of the upstream discovery service");
+                return Ok(Some(snapshot));
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn write_journal(&self, entry: JournalEntry) -> anyhow::Result<()> {
+        match &self.journal {
+            Some(journal) => journal.append(&entry).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl StateStore for FileStore {
+    async fn load(&self) -> anyhow::Result<Option<Snapshot>> {
+        let snapshot = self.load_snapshot().await?;
+        let Some(journal) = &self.journal else {
+            return Ok(snapshot);
+        };
+
+        let changes = journal
+            .replay(snapshot.as_ref().map_or(0, |snapshot| snapshot.journal_generation))
+            .await?;
+        if changes.is_empty() {
+            return Ok(snapshot);
+        }
+
+        // the journaled expirations are wall-clock times, just like the ones of a snapshot taken now
+        let snapshot = snapshot.unwrap_or_else(|| Snapshot {
+            snapshot_time: Some(self.clock.wall()),
+            journal_generation: 0,
+            clusters: Vec::new(),
+        });
+        Ok(Some(Snapshot {
+            clusters: journal::apply(snapshot.clusters, changes),
+            ..snapshot
+        }))
+    }
+
+    async fn save(&self, clusters: &ClusterStore) -> anyhow::Result<usize> {
+        // changes from now on go to a new journal segment, which is replayed on top of this snapshot
+        let journal_generation = match &self.journal {
+            Some(journal) => journal.rotate().await?,
+            None => 0,
+        };
+
+        // all clusters share one time base, so the snapshot is consistent even if the clock steps meanwhile
+        let base = self.clock.time_base();
+        let snapshot = Snapshot {
+            snapshot_time: Some(base.wall),
+            journal_generation,
+            clusters: clusters.backup(&base).await,
+        };
+
+        if let Some(backup_files) = &self.backup_files {
+            let contents = snapshot::encode(&snapshot)?;
+            match &self.backup_keys {
+                Some(keys) => backup_files.write(&keys.encrypt(&contents)?).await?,
+                None => backup_files.write(&contents).await?,
+            }
+        }
+        if let Some(upstream_snapshot_files) = &self.upstream_snapshot_files {
+            upstream_snapshot_files
+                .write(&snapshot::encode_upstream(&snapshot)?)
+                .await?;
+        }
+
+        if let Some(journal) = &self.journal {
+            journal.compact(journal_generation).await?;
+        }
+
+        Ok(snapshot.clusters.len())
+    }
+
+    async fn upsert_affiliate(&self, cluster_id: &ClusterId, affiliate: AffiliateBackup) -> anyhow::Result<()> {
+        self.write_journal(JournalEntry {
+            cluster_id: cluster_id.clone(),
+            change: Some(Change::Update(affiliate)),
+            deleted: None,
+        })
+        .await
+    }
+
+    async fn delete_affiliate(&self, cluster_id: &ClusterId, affiliate_id: &str) -> anyhow::Result<()> {
+        self.write_journal(JournalEntry {
+            cluster_id: cluster_id.clone(),
+            change: Some(Change::Delete(affiliate_id.to_string())),
+            deleted: None,
+        })
+        .await
+    }
+
+    // the snapshots only contain the clusters in memory, which expire on their own
+    async fn expire(&self, _now: SystemTime) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+}
@@ -0,0 +1,20 @@
+use discovery_api::AffiliateUpdateRequest;
+
+/// Returns an update of the affiliate "affiliate" in the cluster "cluster", shared by the tests of all modules.
+pub(crate) fn update_request(ttl_seconds: i64) -> AffiliateUpdateRequest {
+    AffiliateUpdateRequest {
+        cluster_id: "cluster".to_string(),
+        affiliate_id: "affiliate".to_string(),
+        affiliate_data: Some(b"data".to_vec()),
+        affiliate_endpoints: vec![b"endpoint".to_vec()],
+        ttl: Some(prost_types::Duration {
+            seconds: ttl_seconds,
+            nanos: 0,
+        }),
+    }
+}
+
+/// Returns a new temporary directory, which is removed with its contents when it's dropped.
+pub(crate) fn temp_dir() -> tempfile::TempDir {
+    tempfile::Builder::new().prefix("discovery-").tempdir().unwrap()
+}
@@ -0,0 +1,435 @@
+use anyhow::{bail, Context};
+use prost::{Message, Oneof};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+use tracing::{info, warn};
+
+use crate::{
+    encryption::{self, BackupKeys},
+    snapshot::{AffiliateBackup, ClusterBackup},
+};
+
+// Every record is framed as
+//
+//   payload length: u32 | CRC32 of the length: u32 | CRC32 of the payload: u32 | payload
+//
+// with little endian integers. The payload is a JournalEntry, encrypted like the backups if backup keys are given.
+// The length has its own checksum, so a corrupted length isn't mistaken for a torn record at the end.
+const RECORD_HEADER_LEN: usize = 4 + 4 + 4;
+
+/// Durability of journal writes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum JournalSync {
+    /// No journal, changes since the last backup are lost on a crash
+    Off,
+    /// Every change is synced to disk before it's acknowledged
+    #[default]
+    Always,
+    /// Changes are acknowledged once written and synced to disk periodically, batching the syncs
+    Interval,
+}
+
+/// Change of a single affiliate.
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct JournalEntry {
+    #[prost(string, tag = "1")]
+    pub cluster_id: String,
+    #[prost(oneof = "Change", tags = "2, 3")]
+    pub change: Option<Change>,
+    // time of a deletion, it wins over older updates when instances merge their states
+    #[prost(message, optional, tag = "4")]
+    pub deleted: Option<prost_types::Timestamp>,
+}
+
+#[derive(Clone, PartialEq, Oneof)]
+pub(crate) enum Change {
+    // complete state of the affiliate after the update
+    #[prost(message, tag = "2")]
+    Update(AffiliateBackup),
+    // ID of the deleted affiliate
+    #[prost(string, tag = "3")]
+    Delete(String),
+}
+
+/// Applies journal entries on top of the clusters of a snapshot.
+pub(crate) fn apply(clusters: Vec<ClusterBackup>, entries: Vec<JournalEntry>) -> Vec<ClusterBackup> {
+    let mut clusters = clusters
+        .into_iter()
+        .map(|cluster| {
+            let affiliates = cluster
+                .affiliates
+                .into_iter()
+                .map(|affiliate| (affiliate.id.clone(), affiliate))
+                .collect::<HashMap<_, _>>();
+            (cluster.id, affiliates)
+        })
+        .collect::<HashMap<_, _>>();
+
+    for entry in entries {
+        let affiliates = clusters.entry(entry.cluster_id).or_default();
+        match entry.change {
+            Some(Change::Update(affiliate)) => {
+                affiliates.insert(affiliate.id.clone(), affiliate);
+            }
+            Some(Change::Delete(affiliate_id)) => {
+                affiliates.remove(&affiliate_id);
+            }
+            None => (),
+        }
+    }
+
+    clusters
+        .into_iter()
+        .filter(|(_, affiliates)| !affiliates.is_empty())
+        .map(|(id, affiliates)| ClusterBackup {
+            id,
+            affiliates: affiliates.into_values().collect(),
+        })
+        .collect()
+}
+
+struct Segment {
+    generation: u64,
+    file: File,
+}
+
+/// Append-only log of the changes since the last snapshot.
+///
+/// The journal is split into segments with the generation as suffix, e.g. `path.3`. Every snapshot starts a new
+/// segment and records its generation. The older segments are only removed once the snapshot has been written, so
+/// they are replayed on top of the previous snapshot if that fails.
+pub(crate) struct Journal {
+    path: PathBuf,
+    keys: Option<BackupKeys>,
+    pub sync: JournalSync,
+    segment: Mutex<Segment>,
+    // whether the current segment has writes which aren't synced yet
+    unsynced: AtomicBool,
+}
+
+impl Journal {
+    /// Opens a new segment after all existing ones, which are left for replay.
+    pub async fn open(path: PathBuf, keys: Option<BackupKeys>, sync: JournalSync) -> anyhow::Result<Self> {
+        let generation = Self::generations(&path).await?.last().map_or(0, |last| last + 1);
+        let file = Self::create_segment(&path, generation).await?;
+
+        Ok(Self {
+            path,
+            keys,
+            sync,
+            segment: Mutex::new(Segment { generation, file }),
+            unsynced: AtomicBool::new(false),
+        })
+    }
+
+    fn segment_path(path: &Path, generation: u64) -> PathBuf {
+        let mut path = OsString::from(path.as_os_str());
+        path.push(format!(".{generation}"));
+        PathBuf::from(path)
+    }
+
+    async fn create_segment(path: &Path, generation: u64) -> anyhow::Result<File> {
+        let segment_path = Self::segment_path(path, generation);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&segment_path)
+            .await
+            .with_context(|| format!("couldn't open journal {}", segment_path.display()))?;
+
+        // persist the new directory entry
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir).await?.sync_all().await?;
+
+        Ok(file)
+    }
+
+    // generations of the existing segments in ascending order
+    async fn generations(path: &Path) -> anyhow::Result<Vec<u64>> {
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            bail!("invalid journal path {}", path.display());
+        };
+        let prefix = format!("{}.", name.to_string_lossy());
+
+        let mut generations = Vec::new();
+        let mut entries = fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let generation = entry
+                .file_name()
+                .to_str()
+                .and_then(|file_name| file_name.strip_prefix(&prefix))
+                .and_then(|generation| generation.parse::<u64>().ok());
+            generations.extend(generation);
+        }
+        generations.sort_unstable();
+
+        Ok(generations)
+    }
+
+    pub async fn append(&self, entry: &JournalEntry) -> anyhow::Result<()> {
+        let payload = match &self.keys {
+            Some(keys) => keys.encrypt(&entry.encode_to_vec())?,
+            None => entry.encode_to_vec(),
+        };
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        let length = u32::try_from(payload.len())?.to_le_bytes();
+        record.extend_from_slice(&length);
+        record.extend_from_slice(&crc32fast::hash(&length).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+
+        let mut segment = self.segment.lock().await;
+        segment.file.write_all(&record).await?;
+        segment.file.flush().await?;
+        match self.sync {
+            JournalSync::Always => segment.file.sync_data().await?,
+            _ => self.unsynced.store(true, Ordering::Release),
+        }
+
+        Ok(())
+    }
+
+    /// Syncs the writes since the last sync to disk.
+    pub async fn sync(&self) -> anyhow::Result<()> {
+        let segment = self.segment.lock().await;
+        if self.unsynced.swap(false, Ordering::AcqRel) {
+            segment.file.sync_data().await.inspect_err(|_| {
+                self.unsynced.store(true, Ordering::Release);
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Starts a new segment for the changes after a snapshot and returns its generation.
+    pub async fn rotate(&self) -> anyhow::Result<u64> {
+        let mut segment = self.segment.lock().await;
+        segment.file.sync_data().await?;
+        self.unsynced.store(false, Ordering::Release);
+
+        let generation = segment.generation + 1;
+        segment.file = Self::create_segment(&self.path, generation).await?;
+        segment.generation = generation;
+
+        Ok(generation)
+    }
+
+    /// Removes the segments before `generation`, which are covered by a snapshot.
+    pub async fn compact(&self, generation: u64) -> anyhow::Result<()> {
+        for old in Self::generations(&self.path).await? {
+            if old >= generation {
+                break;
+            }
+            match fs::remove_file(Self::segment_path(&self.path, old)).await {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the entries of the segments from `generation` on, which aren't covered by the restored snapshot.
+    pub async fn replay(&self, generation: u64) -> anyhow::Result<Vec<JournalEntry>> {
+        let current = self.segment.lock().await.generation;
+        let mut entries = Vec::new();
+
+        for segment in Self::generations(&self.path).await? {
+            if segment < generation || segment >= current {
+                continue;
+            }
+            let segment_path = Self::segment_path(&self.path, segment);
+            let contents = fs::read(&segment_path).await?;
+            let before_len = entries.len();
+            self.read_records(&contents, &mut entries)
+                .with_context(|| format!("couldn't replay journal {}", segment_path.display()))?;
+            info!(
+                "Replaying {} changes of journal {}",
+                entries.len() - before_len,
+                segment_path.display()
+            );
+        }
+
+        Ok(entries)
+    }
+
+    fn read_records(&self, mut contents: &[u8], entries: &mut Vec<JournalEntry>) -> anyhow::Result<()> {
+        while !contents.is_empty() {
+            // a crash while appending leaves a torn record at the end, which was never acknowledged
+            let Some(header) = contents.get(..RECORD_HEADER_LEN) else {
+                warn!("Ignoring {} bytes of an incomplete journal record", contents.len());
+                break;
+            };
+            if crc32fast::hash(&header[0..4]) != u32::from_le_bytes(header[4..8].try_into().unwrap()) {
+                bail!("corrupted journal record length followed by {} bytes", contents.len());
+            }
+            let length = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+            let Some(payload) = contents[RECORD_HEADER_LEN..].get(..length) else {
+                warn!("Ignoring {} bytes of an incomplete journal record", contents.len());
+                break;
+            };
+            let checksum = u32::from_le_bytes(header[8..12].try_into().unwrap());
+            let rest = &contents[RECORD_HEADER_LEN + payload.len()..];
+            if crc32fast::hash(payload) != checksum {
+                // anything after a corrupted record was acknowledged, so it can't be dropped silently
+                if !rest.is_empty() {
+                    bail!("corrupted journal record followed by {} bytes", rest.len());
+                }
+                warn!("Ignoring a torn journal record of {} bytes", contents.len());
+                break;
+            }
+            contents = rest;
+
+            let entry = match (&self.keys, encryption::is_encrypted(payload)) {
+                (Some(keys), true) => JournalEntry::decode(keys.decrypt(payload)?.as_slice())?,
+                (None, true) => bail!("journal is encrypted, but no backup key is configured"),
+                (_, false) => JournalEntry::decode(payload)?,
+            };
+            entries.push(entry);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::temp_dir;
+    use tempfile::TempDir;
+
+    fn journal_path() -> (TempDir, PathBuf) {
+        let dir = temp_dir();
+        let path = dir.path().join("journal");
+        (dir, path)
+    }
+
+    fn update(affiliate_id: &str, data: &[u8]) -> JournalEntry {
+        JournalEntry {
+            cluster_id: "cluster".to_string(),
+            change: Some(Change::Update(AffiliateBackup {
+                id: affiliate_id.to_string(),
+                data: data.to_vec(),
+                endpoints: Vec::new(),
+                expiration: None,
+                updated: None,
+            })),
+            deleted: None,
+        }
+    }
+
+    fn delete(affiliate_id: &str) -> JournalEntry {
+        JournalEntry {
+            cluster_id: "cluster".to_string(),
+            change: Some(Change::Delete(affiliate_id.to_string())),
+            deleted: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn entries_are_replayed_from_the_snapshot_generation() {
+        let (_dir, path) = journal_path();
+        let journal = Journal::open(path.clone(), None, JournalSync::Always).await.unwrap();
+        journal.append(&update("first", b"old")).await.unwrap();
+        let generation = journal.rotate().await.unwrap();
+        journal.append(&update("second", b"data")).await.unwrap();
+        journal.append(&delete("first")).await.unwrap();
+        drop(journal);
+
+        let reopened = Journal::open(path.clone(), None, JournalSync::Always).await.unwrap();
+        assert_eq!(reopened.replay(0).await.unwrap().len(), 3);
+        let entries = reopened.replay(generation).await.unwrap();
+        assert_eq!(entries, vec![update("second", b"data"), delete("first")]);
+
+        reopened.compact(generation).await.unwrap();
+        assert_eq!(reopened.replay(0).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn torn_record_is_ignored() {
+        let (_dir, path) = journal_path();
+        let journal = Journal::open(path.clone(), None, JournalSync::Interval).await.unwrap();
+        journal.append(&update("affiliate", b"data")).await.unwrap();
+        journal.append(&update("affiliate", b"torn")).await.unwrap();
+        journal.sync().await.unwrap();
+        drop(journal);
+
+        let segment = Journal::segment_path(&path, 0);
+        let contents = std::fs::read(&segment).unwrap();
+        std::fs::write(&segment, &contents[..contents.len() - 2]).unwrap();
+
+        let reopened = Journal::open(path, None, JournalSync::Always).await.unwrap();
+        assert_eq!(reopened.replay(0).await.unwrap(), vec![update("affiliate", b"data")]);
+    }
+
+    #[tokio::test]
+    async fn corrupted_length_inside_segment_is_an_error() {
+        let (_dir, path) = journal_path();
+        let journal = Journal::open(path.clone(), None, JournalSync::Always).await.unwrap();
+        journal.append(&update("affiliate", b"data")).await.unwrap();
+        journal.append(&update("affiliate", b"last")).await.unwrap();
+        drop(journal);
+
+        // a length beyond the end of the segment must not hide the acknowledged records after it
+        let segment = Journal::segment_path(&path, 0);
+        let mut contents = std::fs::read(&segment).unwrap();
+        contents[1] ^= 0x01;
+        std::fs::write(&segment, &contents).unwrap();
+        let reopened = Journal::open(path, None, JournalSync::Always).await.unwrap();
+        assert!(reopened.replay(0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn corrupted_record_inside_segment_is_an_error() {
+        let (_dir, path) = journal_path();
+        let journal = Journal::open(path.clone(), None, JournalSync::Always).await.unwrap();
+        journal.append(&update("affiliate", b"data")).await.unwrap();
+        journal.append(&update("affiliate", b"last")).await.unwrap();
+        drop(journal);
+
+        let segment = Journal::segment_path(&path, 0);
+        let mut contents = std::fs::read(&segment).unwrap();
+        contents[RECORD_HEADER_LEN] ^= 0xff;
+        std::fs::write(&segment, &contents).unwrap();
+        let reopened = Journal::open(path.clone(), None, JournalSync::Always).await.unwrap();
+        assert!(reopened.replay(0).await.is_err());
+
+        // the same corruption in the final record is a torn write
+        contents[RECORD_HEADER_LEN] ^= 0xff;
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        std::fs::write(&segment, &contents).unwrap();
+        let reopened = Journal::open(path, None, JournalSync::Always).await.unwrap();
+        assert_eq!(reopened.replay(0).await.unwrap(), vec![update("affiliate", b"data")]);
+    }
+
+    #[test]
+    fn entries_are_applied_on_top_of_snapshot() {
+        let snapshot = vec![ClusterBackup {
+            id: "cluster".to_string(),
+            affiliates: vec![AffiliateBackup {
+                id: "first".to_string(),
+                data: b"snapshot".to_vec(),
+                endpoints: Vec::new(),
+                expiration: None,
+                updated: None,
+            }],
+        }];
+
+        let clusters = apply(snapshot.clone(), vec![update("second", b"data")]);
+        assert_eq!(clusters[0].affiliates.len(), 2);
+
+        assert!(apply(snapshot, vec![delete("first")]).is_empty());
+    }
+}
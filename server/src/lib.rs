@@ -0,0 +1,284 @@
+mod backup;
+mod clock;
+mod cluster;
+mod encryption;
+mod expiry;
+mod file_state;
+#[cfg(test)]
+mod fixtures;
+mod journal;
+mod limits;
+mod redb_state;
+mod replication;
+mod service;
+mod snapshot;
+mod state;
+mod store;
+mod validation;
+
+use discovery_api::{cluster_server::ClusterServer, tonic::transport::Server};
+use tonic_health::{server::HealthReporter, ServingStatus};
+
+use anyhow::Context;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    backup::BackupHealth,
+    cluster::LagPolicy,
+    encryption::BackupKeys,
+    journal::JournalSync,
+    limits::{Limits, Mode},
+    replication::{replication_server::ReplicationServer, ReplicationToken},
+    state::StateBackend,
+};
+
+// public for the benchmarks
+pub use crate::{
+    clock::SystemClock,
+    service::{DiscoveryService, ServiceOptions},
+};
+
+#[derive(clap::Parser, Debug, Clone)]
+#[clap(version = "1.0", next_line_help = true)]
+pub struct Config {
+    // Listen port
+    #[clap(long, env = "PORT", default_value = "3000")]
+    pub port: u16,
+
+    // Garbage collection interval
+    #[clap(long, env = "GC_INTERVAL", default_value = "60")]
+    pub gc_interval: u16,
+
+    // Storage backend of the cluster state
+    #[clap(long, env = "STATE_BACKEND", value_enum, default_value_t)]
+    pub state_backend: StateBackend,
+
+    // Backup path
+    #[clap(long, env = "BACKUP_PATH")]
+    pub backup_path: Option<String>,
+
+    // Backup interval
+    #[clap(long, env = "BACKUP_INTERVAL", default_value = "600")]
+    pub backup_interval: u16,
+
+    // Number of backup generations kept
+    #[clap(long, env = "BACKUP_GENERATIONS", default_value = "3", value_parser = clap::value_parser!(u16).range(1..))]
+    pub backup_generations: u16,
+
+    // State file of the upstream discovery service, imported if there is no backup and rewritten with every backup
+    #[clap(long, env = "UPSTREAM_SNAPSHOT_PATH")]
+    pub upstream_snapshot_path: Option<String>,
+
+    // Journal of the changes between backups in the backup path, every change is synced before it is acknowledged by default
+    #[clap(long, env = "JOURNAL_SYNC", value_enum, default_value_t)]
+    pub journal_sync: JournalSync,
+
+    // Time in milliseconds between syncs of the journal to disk, if they are batched
+    #[clap(long, env = "JOURNAL_SYNC_INTERVAL", default_value = "100", value_parser = clap::value_parser!(u16).range(1..))]
+    pub journal_sync_interval: u16,
+
+    // Hex encoded 256-bit keys encrypting the backups, separated by commas. The first key encrypts, all of them are
+    // tried to decrypt, so keys can be rotated
+    #[clap(long, env = "BACKUP_KEYS", hide_env_values = true)]
+    pub backup_keys: Option<BackupKeys>,
+
+    // File with the backup keys, one per line, instead of BACKUP_KEYS
+    #[clap(long, env = "BACKUP_KEY_FILE", conflicts_with = "backup_keys")]
+    pub backup_key_file: Option<String>,
+
+    // Address of the replication service, e.g. 0.0.0.0:3001. It's only served on its own listener, which should only
+    // be reachable by the other instances, and requires REPLICATION_TOKEN
+    #[clap(long, env = "REPLICATION_LISTEN_ADDR", requires = "replication_token")]
+    pub replication_listen_addr: Option<SocketAddr>,
+
+    // Shared secret the instances present to each other's replication service
+    #[clap(long, env = "REPLICATION_TOKEN", hide_env_values = true)]
+    pub replication_token: Option<ReplicationToken>,
+
+    // URL of the replication service of the primary instance, e.g. http://primary:3001. This instance then runs as a
+    // passive standby which replicates the state of the primary and rejects writes until it's promoted with the
+    // Promote call of its own replication service. The primary should persist its state: after a restart without it,
+    // the standby keeps the affiliates missing on the primary for MAX_TTL instead of mirroring its state exactly
+    #[clap(
+        long,
+        env = "REPLICATE_FROM",
+        requires = "primary_endpoint",
+        requires = "replication_listen_addr"
+    )]
+    pub replicate_from: Option<String>,
+
+    // Endpoint clients are redirected to while this instance is a passive standby, e.g. primary:3000
+    #[clap(long, env = "PRIMARY_ENDPOINT", requires = "replicate_from")]
+    pub primary_endpoint: Option<String>,
+
+    // URLs of the replication services of the other instances in active-active mode, separated by commas. All
+    // instances accept writes and exchange their changes, the latest write of every affiliate wins
+    #[clap(
+        long,
+        env = "PEERS",
+        value_delimiter = ',',
+        conflicts_with = "replicate_from",
+        requires = "replication_listen_addr"
+    )]
+    pub peers: Vec<String>,
+
+    // Time in seconds restored affiliates are kept for their clients to re-register, 0 disables it. Defaults to 0
+    // in compat mode and 60 in hardened mode
+    #[clap(long, env = "RESTORE_GRACE_PERIOD")]
+    pub restore_grace_period: Option<u16>,
+
+    // Behaviour of watch streams lagging behind
+    #[clap(long, env = "WATCH_LAG_POLICY", value_enum, default_value_t)]
+    pub watch_lag_policy: LagPolicy,
+
+    // Validation and semantics: upstream compatible or hardened
+    #[clap(long, env = "SERVER_MODE", value_enum, default_value_t)]
+    pub server_mode: Mode,
+
+    // The following limits default to the values of the server mode
+
+    // Maximum length of cluster and affiliate IDs
+    #[clap(long, env = "MAX_IDENTIFIER_LENGTH")]
+    pub max_identifier_length: Option<usize>,
+
+    // Maximum length of affiliate data in bytes
+    #[clap(long, env = "MAX_PAYLOAD_LENGTH")]
+    pub max_payload_length: Option<usize>,
+
+    // Maximum length of each affiliate endpoint in bytes
+    #[clap(long, env = "MAX_ENDPOINT_LENGTH")]
+    pub max_endpoint_length: Option<usize>,
+
+    // Maximum affiliate TTL in seconds
+    #[clap(long, env = "MAX_TTL")]
+    pub max_ttl: Option<u64>,
+
+    // Watch buffer size in messages
+    #[clap(long, env = "WATCH_BUFFER_SIZE")]
+    pub watch_buffer_size: Option<usize>,
+
+    // Maximum number of affiliates per cluster
+    #[clap(long, env = "MAX_CLUSTER_AFFILIATES")]
+    pub max_cluster_affiliates: Option<usize>,
+
+    // Maximum number of endpoints per affiliate
+    #[clap(long, env = "MAX_AFFILIATE_ENDPOINTS")]
+    pub max_affiliate_endpoints: Option<usize>,
+
+    // Maximum size of all affiliates of a cluster in bytes
+    #[clap(long, env = "MAX_CLUSTER_BYTES")]
+    pub max_cluster_bytes: Option<usize>,
+}
+
+pub async fn run(config: Config) -> anyhow::Result<()> {
+    let mode_limits = Limits::for_mode(config.server_mode);
+    let limits = Limits {
+        max_identifier_length: config
+            .max_identifier_length
+            .unwrap_or(mode_limits.max_identifier_length),
+        max_payload_length: config.max_payload_length.unwrap_or(mode_limits.max_payload_length),
+        max_endpoint_length: config.max_endpoint_length.unwrap_or(mode_limits.max_endpoint_length),
+        max_ttl: config.max_ttl.map(Duration::from_secs).unwrap_or(mode_limits.max_ttl),
+        buffer_size: config.watch_buffer_size.unwrap_or(mode_limits.buffer_size),
+        max_affiliates: config.max_cluster_affiliates.unwrap_or(mode_limits.max_affiliates),
+        max_endpoints: config.max_affiliate_endpoints.unwrap_or(mode_limits.max_endpoints),
+        max_bytes: config.max_cluster_bytes.unwrap_or(mode_limits.max_bytes),
+    };
+    limits.validate()?;
+
+    tracing::info!("Effective configuration: {:?}", config);
+    tracing::info!("Running in {} mode with {:?}", config.server_mode, limits);
+    if config.server_mode == Mode::Compat && limits != mode_limits {
+        tracing::warn!("Limits deviate from the upstream discovery service");
+    }
+
+    let backup_keys = match &config.backup_key_file {
+        Some(path) => Some(BackupKeys::from_file(Path::new(path))?),
+        None => config.backup_keys.clone(),
+    };
+    if backup_keys.is_some() && config.upstream_snapshot_path.is_some() {
+        tracing::warn!("Upstream snapshots are written unencrypted");
+    }
+
+    let options = ServiceOptions {
+        gc_interval: Duration::from_secs(config.gc_interval.into()),
+        state_backend: config.state_backend,
+        backup_path: config.backup_path.map(PathBuf::from),
+        backup_interval: Duration::from_secs(config.backup_interval.into()),
+        backup_generations: config.backup_generations.into(),
+        upstream_snapshot_path: config.upstream_snapshot_path.map(PathBuf::from),
+        backup_keys,
+        journal_sync: config.journal_sync,
+        journal_sync_interval: Duration::from_millis(config.journal_sync_interval.into()),
+        replicate_from: config.replicate_from,
+        primary_endpoint: config.primary_endpoint,
+        peers: config.peers,
+        replication_token: config.replication_token.clone(),
+        watch_lag_policy: config.watch_lag_policy,
+        mode: config.server_mode,
+        limits,
+        restore_grace_period: config
+            .restore_grace_period
+            .map(|period| Duration::from_secs(period.into()))
+            .unwrap_or(config.server_mode.restore_grace_period()),
+    };
+    let discovery_service = DiscoveryService::new(options, Arc::new(SystemClock)).await?;
+
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter.set_serving::<ClusterServer<DiscoveryService>>().await;
+    tokio::spawn(report_backup_health(health_reporter, discovery_service.backup_health()));
+
+    let replication_service = discovery_service.clone();
+    let discovery_service = ClusterServer::new(discovery_service);
+    let addr = format!("0.0.0.0:{}", config.port).parse().unwrap();
+
+    tracing::info!("Starting Talos Discovery Service gRPC server: {}", addr);
+    let discovery_server = Server::builder()
+        .add_service(health_service)
+        .add_service(discovery_service)
+        .serve(addr);
+
+    // replication is kept off the public port, clients must not be able to read or promote the state
+    let Some(replication_addr) = config.replication_listen_addr else {
+        discovery_server.await?;
+        return Ok(());
+    };
+    let token = config
+        .replication_token
+        .context("the replication service requires a replication token")?;
+    let replication_service = ReplicationServer::with_interceptor(replication_service, token);
+
+    tracing::info!("Starting replication gRPC server: {}", replication_addr);
+    let replication_server = Server::builder()
+        .add_service(replication_service)
+        .serve(replication_addr);
+    tokio::try_join!(discovery_server, replication_server)?;
+
+    Ok(())
+}
+
+// readiness checks can watch the persistence service to notice failing backups
+async fn report_backup_health(reporter: HealthReporter, backup_health: Arc<BackupHealth>) {
+    let mut degraded = backup_health.subscribe();
+    loop {
+        let status = if *degraded.borrow_and_update() {
+            tracing::warn!(
+                "Persistence degraded, {} backups failed so far",
+                backup_health.failures()
+            );
+            ServingStatus::NotServing
+        } else {
+            ServingStatus::Serving
+        };
+        reporter.set_service_status(BackupHealth::SERVICE_NAME, status).await;
+
+        if degraded.changed().await.is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::ensure;
+use std::{fmt, time::Duration};
+
+/// Validation and semantics the server follows.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Mode {
+    /// Behave exactly like the upstream Sidero discovery service
+    Compat,
+    /// Enforce custom limits on top of the upstream validation
+    #[default]
+    Hardened,
+}
+
+impl Mode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Compat => "compat",
+            Mode::Hardened => "hardened",
+        }
+    }
+
+    /// Time restored affiliates are kept for their clients to re-register by default.
+    pub fn restore_grace_period(&self) -> Duration {
+        match self {
+            // upstream expires restored affiliates right away
+            Mode::Compat => Duration::ZERO,
+            // XXX: custom extension
+            Mode::Hardened => Duration::from_secs(60),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Limits enforced on requests and on the state of a cluster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub max_identifier_length: usize,
+    pub max_payload_length: usize,
+    pub max_endpoint_length: usize,
+    pub max_ttl: Duration,
+    // capacity of the watch broadcast and watch stream buffers
+    pub buffer_size: usize,
+    pub max_affiliates: usize,
+    pub max_endpoints: usize,
+    // sum of identifiers, data and endpoints of all affiliates of a cluster
+    pub max_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::for_mode(Mode::default())
+    }
+}
+
+impl Limits {
+    // endpoints are merged by a linear search, so an affiliate can't be allowed arbitrarily many
+    const ENDPOINTS_CEILING: usize = 4096;
+
+    pub fn for_mode(mode: Mode) -> Self {
+        match mode {
+            // limits of the upstream discovery service
+            Mode::Compat => Self {
+                max_identifier_length: 256,
+                max_payload_length: 2048,
+                max_endpoint_length: 32,
+                max_ttl: Duration::from_secs(30 * 60), // 30 minutes
+                buffer_size: 64,
+                max_affiliates: 1000,
+                max_endpoints: 64,
+                max_bytes: usize::MAX,
+            },
+            // XXX: custom extension
+            Mode::Hardened => Self {
+                max_identifier_length: 256,
+                max_payload_length: 512 * 1024,
+                max_endpoint_length: 512 * 1024,
+                max_ttl: Duration::from_secs(2 * 60 * 60), // 2 hours
+                buffer_size: 64,
+                max_affiliates: 1000,
+                max_endpoints: 64,
+                max_bytes: 16 * 1024 * 1024,
+            },
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            self.max_identifier_length > 0,
+            "maximum identifier length must be positive"
+        );
+        ensure!(self.max_payload_length > 0, "maximum payload length must be positive");
+        ensure!(self.max_endpoint_length > 0, "maximum endpoint length must be positive");
+        ensure!(!self.max_ttl.is_zero(), "maximum TTL must be positive");
+        ensure!(self.buffer_size > 0, "buffer size must be positive");
+        ensure!(self.max_affiliates > 0, "maximum number of affiliates must be positive");
+        ensure!(self.max_endpoints > 0, "maximum number of endpoints must be positive");
+        ensure!(
+            self.max_endpoints <= Self::ENDPOINTS_CEILING,
+            "maximum number of endpoints must not exceed {}",
+            Self::ENDPOINTS_CEILING
+        );
+        ensure!(
+            self.max_bytes >= self.max_payload_length,
+            "maximum cluster size must fit at least one payload"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_limit_is_validated() {
+        assert!(Limits::default().validate().is_ok());
+
+        for max_endpoints in [0, Limits::ENDPOINTS_CEILING + 1, usize::MAX] {
+            let limits = Limits {
+                max_endpoints,
+                ..Default::default()
+            };
+            assert!(limits.validate().is_err());
+        }
+    }
+}
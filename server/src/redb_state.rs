@@ -0,0 +1,289 @@
+use discovery_api::tonic::async_trait;
+use prost::Message;
+use redb::{Database, ReadableTable, TableDefinition};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    clock::Clock,
+    cluster::ClusterId,
+    snapshot::{AffiliateBackup, ClusterBackup, Snapshot},
+    state::StateStore,
+    store::ClusterStore,
+};
+
+// affiliates keyed by cluster ID and affiliate ID
+const AFFILIATES: TableDefinition<(&str, &str), &[u8]> = TableDefinition::new("affiliates");
+// index of the affiliates by expiration in milliseconds since the Unix epoch
+const EXPIRATIONS: TableDefinition<(u64, &str, &str), ()> = TableDefinition::new("expirations");
+
+fn expiration_millis(affiliate: &AffiliateBackup) -> u64 {
+    affiliate
+        .expiration
+        .and_then(|expiration| SystemTime::try_from(expiration).ok())
+        .and_then(|expiration| expiration.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |expiration| expiration.as_millis() as u64)
+}
+
+/// Store persisting every change in an embedded redb database.
+///
+/// Every change is committed before it's acknowledged, so a restart restores the exact state. The periodic saves
+/// have nothing left to do.
+pub(crate) struct RedbStore {
+    db: Arc<Database>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RedbStore {
+    pub const DATABASE_FILE_NAME: &str = "discovery_service_state.redb";
+
+    pub async fn open(path: PathBuf, clock: Arc<dyn Clock>) -> anyhow::Result<Self> {
+        let db = tokio::task::spawn_blocking(move || -> anyhow::Result<Database> {
+            let db = Database::create(path)?;
+
+            // creates the tables, so reads of a new database don't fail
+            let txn = db.begin_write()?;
+            txn.open_table(AFFILIATES)?;
+            txn.open_table(EXPIRATIONS)?;
+            txn.commit()?;
+
+            Ok(db)
+        })
+        .await??;
+
+        Ok(Self {
+            db: Arc::new(db),
+            clock,
+        })
+    }
+
+    // redb blocks on disk I/O, so transactions run on the blocking thread pool
+    async fn with_db<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Database) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || f(&db)).await?
+    }
+}
+
+#[async_trait]
+impl StateStore for RedbStore {
+    fn is_exact(&self) -> bool {
+        true
+    }
+
+    async fn load(&self) -> anyhow::Result<Option<Snapshot>> {
+        let clusters = self
+            .with_db(|db| {
+                let txn = db.begin_read()?;
+                let affiliates = txn.open_table(AFFILIATES)?;
+
+                let mut clusters = BTreeMap::<String, Vec<AffiliateBackup>>::new();
+                for entry in affiliates.iter()? {
+                    let (key, value) = entry?;
+                    let (cluster_id, _) = key.value();
+                    clusters
+                        .entry(cluster_id.to_string())
+                        .or_default()
+                        .push(AffiliateBackup::decode(value.value())?);
+                }
+                Ok(clusters)
+            })
+            .await?;
+
+        if clusters.is_empty() {
+            return Ok(None);
+        }
+
+        // the stored expirations are wall-clock times, so the state is as current as a snapshot taken now
+        Ok(Some(Snapshot {
+            snapshot_time: Some(self.clock.wall()),
+            journal_generation: 0,
+            clusters: clusters
+                .into_iter()
+                .map(|(id, affiliates)| ClusterBackup { id, affiliates })
+                .collect(),
+        }))
+    }
+
+    async fn save(&self, _clusters: &ClusterStore) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+
+    async fn upsert_affiliate(&self, cluster_id: &ClusterId, affiliate: AffiliateBackup) -> anyhow::Result<()> {
+        let cluster_id = cluster_id.clone();
+        self.with_db(move |db| {
+            let key = (cluster_id.as_str(), affiliate.id.as_str());
+
+            let txn = db.begin_write()?;
+            {
+                let mut affiliates = txn.open_table(AFFILIATES)?;
+                let mut expirations = txn.open_table(EXPIRATIONS)?;
+
+                let previous = affiliates
+                    .insert(key, affiliate.encode_to_vec().as_slice())?
+                    .map(|previous| AffiliateBackup::decode(previous.value()))
+                    .transpose()?;
+                if let Some(previous) = previous {
+                    expirations.remove((expiration_millis(&previous), key.0, key.1))?;
+                }
+                expirations.insert((expiration_millis(&affiliate), key.0, key.1), ())?;
+            }
+            txn.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_affiliate(&self, cluster_id: &ClusterId, affiliate_id: &str) -> anyhow::Result<()> {
+        let (cluster_id, affiliate_id) = (cluster_id.clone(), affiliate_id.to_string());
+        self.with_db(move |db| {
+            let key = (cluster_id.as_str(), affiliate_id.as_str());
+
+            let txn = db.begin_write()?;
+            {
+                let mut affiliates = txn.open_table(AFFILIATES)?;
+                let mut expirations = txn.open_table(EXPIRATIONS)?;
+
+                let previous = affiliates
+                    .remove(key)?
+                    .map(|previous| AffiliateBackup::decode(previous.value()))
+                    .transpose()?;
+                if let Some(previous) = previous {
+                    expirations.remove((expiration_millis(&previous), key.0, key.1))?;
+                }
+            }
+            txn.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn expire(&self, now: SystemTime) -> anyhow::Result<usize> {
+        let now = now.duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as u64);
+        self.with_db(move |db| {
+            let txn = db.begin_write()?;
+            let expired = {
+                let mut affiliates = txn.open_table(AFFILIATES)?;
+                let mut expirations = txn.open_table(EXPIRATIONS)?;
+
+                let expired = expirations
+                    .range(..(now + 1, "", ""))?
+                    .map(|entry| {
+                        let (key, _) = entry?;
+                        let (expiration, cluster_id, affiliate_id) = key.value();
+                        Ok((expiration, cluster_id.to_string(), affiliate_id.to_string()))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+
+                for (expiration, cluster_id, affiliate_id) in &expired {
+                    expirations.remove((*expiration, cluster_id.as_str(), affiliate_id.as_str()))?;
+                    affiliates.remove((cluster_id.as_str(), affiliate_id.as_str()))?;
+                }
+                expired.len()
+            };
+            txn.commit()?;
+
+            Ok(expired)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SystemClock, fixtures::temp_dir};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    async fn open_store() -> (TempDir, RedbStore, PathBuf) {
+        let dir = temp_dir();
+        let path = dir.path().join(RedbStore::DATABASE_FILE_NAME);
+        let store = RedbStore::open(path.clone(), Arc::new(SystemClock)).await.unwrap();
+        (dir, store, path)
+    }
+
+    fn affiliate(id: &str, expiration: SystemTime) -> AffiliateBackup {
+        AffiliateBackup {
+            id: id.to_string(),
+            data: id.as_bytes().to_vec(),
+            endpoints: Vec::new(),
+            expiration: Some(expiration.into()),
+            updated: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_survive_reopening() {
+        let (_dir, store, path) = open_store().await;
+        assert!(store.load().await.unwrap().is_none());
+
+        let expiration = SystemTime::now() + Duration::from_secs(60);
+        let cluster_id = "cluster".to_string();
+        store
+            .upsert_affiliate(&cluster_id, affiliate("first", expiration))
+            .await
+            .unwrap();
+        store
+            .upsert_affiliate(&cluster_id, affiliate("second", expiration))
+            .await
+            .unwrap();
+        store
+            .upsert_affiliate(&"other".to_string(), affiliate("third", expiration))
+            .await
+            .unwrap();
+        store.delete_affiliate(&cluster_id, "first").await.unwrap();
+        drop(store);
+
+        let store = RedbStore::open(path, Arc::new(SystemClock)).await.unwrap();
+        let snapshot = store.load().await.unwrap().unwrap();
+        assert_eq!(
+            snapshot.clusters,
+            vec![
+                ClusterBackup {
+                    id: "cluster".to_string(),
+                    affiliates: vec![affiliate("second", expiration)],
+                },
+                ClusterBackup {
+                    id: "other".to_string(),
+                    affiliates: vec![affiliate("third", expiration)],
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn expiry_scan_removes_due_affiliates() {
+        let (_dir, store, _) = open_store().await;
+        let now = SystemTime::now();
+        let cluster_id = "cluster".to_string();
+
+        store
+            .upsert_affiliate(&cluster_id, affiliate("refreshed", now - Duration::from_secs(10)))
+            .await
+            .unwrap();
+        store
+            .upsert_affiliate(&cluster_id, affiliate("refreshed", now + Duration::from_secs(60)))
+            .await
+            .unwrap();
+        store
+            .upsert_affiliate(&cluster_id, affiliate("expired", now - Duration::from_secs(10)))
+            .await
+            .unwrap();
+
+        assert_eq!(store.expire(now).await.unwrap(), 1);
+        let snapshot = store.load().await.unwrap().unwrap();
+        assert_eq!(snapshot.clusters[0].affiliates[0].id, "refreshed");
+
+        assert_eq!(store.expire(now + Duration::from_secs(60)).await.unwrap(), 1);
+        assert!(store.load().await.unwrap().is_none());
+    }
+}
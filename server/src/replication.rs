@@ -0,0 +1,215 @@
+use anyhow::ensure;
+use discovery_api::tonic::{
+    metadata::{Ascii, MetadataValue},
+    service::Interceptor,
+    Request, Status,
+};
+use prost::{Message, Oneof};
+use std::{fmt, str::FromStr};
+use tokio::sync::{broadcast, watch};
+
+use crate::{journal::JournalEntry, snapshot::ClusterBackup};
+
+include!(concat!(env!("OUT_DIR"), "/talos.discovery.replication.Replication.rs"));
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SubscribeRequest {}
+
+/// Event of the replication stream, the first one is a snapshot of all clusters, followed by every change.
+#[derive(Clone, PartialEq, Message)]
+pub struct ReplicationEvent {
+    #[prost(oneof = "Event", tags = "1, 2")]
+    pub event: Option<Event>,
+}
+
+#[derive(Clone, PartialEq, Oneof)]
+pub enum Event {
+    #[prost(message, tag = "1")]
+    Snapshot(ReplicationSnapshot),
+    #[prost(message, tag = "2")]
+    Change(JournalEntry),
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ReplicationSnapshot {
+    // expirations are wall-clock times, so the clocks of the instances should be synchronized
+    #[prost(message, repeated, tag = "1")]
+    pub clusters: Vec<ClusterBackup>,
+    // random ID of the instance, which changes with every restart
+    #[prost(string, tag = "2")]
+    pub instance_id: String,
+    // whether the instance restores its state after a restart
+    #[prost(bool, tag = "3")]
+    pub persistent: bool,
+    // explicit deletions which are still remembered, only merged by peers
+    #[prost(message, repeated, tag = "4")]
+    pub deletions: Vec<JournalEntry>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PromoteRequest {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PromoteResponse {}
+
+/// Instance a state is replicated from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Source {
+    // the state of the primary replaces the one of its standby
+    Primary,
+    // the states of peers are merged, the latest write of every affiliate wins
+    Peer,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Primary => write!(f, "primary"),
+            Source::Peer => write!(f, "peer"),
+        }
+    }
+}
+
+/// Role of an instance in a primary/standby pair.
+///
+/// Every instance passes its changes on to the standbys and peers subscribed to it. A standby replicates the state of
+/// its primary and doesn't accept writes until it's promoted.
+pub(crate) struct Replicator {
+    changes: broadcast::Sender<JournalEntry>,
+    // client endpoint of the primary while this instance is a passive standby
+    primary: watch::Sender<Option<String>>,
+}
+
+impl Replicator {
+    // changes buffered per standby, a standby falling further behind is resynced with a new snapshot
+    const BUFFER_SIZE: usize = 4096;
+
+    pub fn new(primary: Option<String>) -> Self {
+        Self {
+            changes: broadcast::Sender::new(Self::BUFFER_SIZE),
+            primary: watch::Sender::new(primary),
+        }
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        self.changes.receiver_count() > 0
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<JournalEntry> {
+        self.changes.subscribe()
+    }
+
+    pub fn publish(&self, entry: JournalEntry) {
+        // there's nobody to miss the change without subscribers
+        let _ = self.changes.send(entry);
+    }
+
+    /// Returns the endpoint of the primary while this instance is a passive standby.
+    pub fn primary(&self) -> Option<String> {
+        self.primary.borrow().clone()
+    }
+
+    /// Makes the instance accept writes and returns whether it was a passive standby before.
+    pub fn promote(&self) -> bool {
+        self.primary.send_replace(None).is_some()
+    }
+
+    /// Waits until the instance is promoted.
+    pub async fn promoted(&self) {
+        let _ = self.primary.subscribe().wait_for(Option::is_none).await;
+    }
+}
+
+/// Shared secret instances present to each other's replication service.
+#[derive(Clone)]
+pub struct ReplicationToken {
+    // value of the authorization header
+    header: MetadataValue<Ascii>,
+}
+
+// never leaks the token into the logs
+impl fmt::Debug for ReplicationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ReplicationToken(redacted)")
+    }
+}
+
+impl FromStr for ReplicationToken {
+    type Err = anyhow::Error;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        ensure!(!token.is_empty(), "replication token is empty");
+        ensure!(
+            token.bytes().all(|byte| byte.is_ascii_graphic()),
+            "replication token contains characters other than printable ASCII"
+        );
+        let header = format!("Bearer {token}").parse()?;
+        Ok(Self { header })
+    }
+}
+
+impl ReplicationToken {
+    const METADATA_KEY: &str = "authorization";
+
+    /// Wraps a message to the replication service of another instance in an authorized request.
+    pub fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request.metadata_mut().insert(Self::METADATA_KEY, self.header.clone());
+        request
+    }
+}
+
+// every byte is compared, so the time taken doesn't tell how much of a guessed token matched
+impl Interceptor for ReplicationToken {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let presented = request
+            .metadata()
+            .get(Self::METADATA_KEY)
+            .map_or(&[][..], MetadataValue::as_bytes);
+        let expected = self.header.as_bytes();
+        let difference = presented
+            .iter()
+            .zip(expected)
+            .fold(0, |difference, (presented, expected)| {
+                difference | (presented ^ expected)
+            });
+        if presented.len() != expected.len() || difference != 0 {
+            return Err(Status::unauthenticated("invalid replication token"));
+        }
+
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_need_the_exact_token() {
+        let mut token = "secret".parse::<ReplicationToken>().unwrap();
+        let request = |token: &ReplicationToken| token.request(());
+
+        assert!(token.call(request(&token.clone())).is_ok());
+        for other in ["secre", "secret2", "Secret"] {
+            let status = token.call(request(&other.parse().unwrap())).unwrap_err();
+            assert_eq!(status.code(), discovery_api::tonic::Code::Unauthenticated);
+        }
+        assert!(token.call(Request::new(())).is_err());
+
+        assert!("".parse::<ReplicationToken>().is_err());
+        assert!("s\u{e9}cret".parse::<ReplicationToken>().is_err());
+        assert_eq!(format!("{token:?}"), "ReplicationToken(redacted)");
+    }
+
+    #[tokio::test]
+    async fn promotion_ends_passive_standby() {
+        let replicator = Replicator::new(Some("primary:3000".to_string()));
+        assert_eq!(replicator.primary(), Some("primary:3000".to_string()));
+
+        assert!(replicator.promote());
+        replicator.promoted().await;
+        assert_eq!(replicator.primary(), None);
+        assert!(!replicator.promote());
+    }
+}
@@ -1,77 +1,230 @@
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use anyhow::{bail, ensure};
 use discovery_api::{
     self,
     cluster_server::Cluster,
-    tonic::{async_trait, Request, Response, Status},
+    tonic::{async_trait, metadata::MetadataValue, Request, Response, Status},
     AffiliateDeleteRequest, AffiliateDeleteResponse, AffiliateUpdateRequest, AffiliateUpdateResponse, HelloRequest,
-    HelloResponse, ListRequest, ListResponse, WatchRequest, WatchResponse,
+    HelloResponse, ListRequest, ListResponse, RedirectMessage, WatchRequest, WatchResponse,
+};
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
-use std::{collections::HashMap, net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
-use tokio::io::AsyncWriteExt;
 use tokio::{
-    fs::{File, OpenOptions},
-    sync::Mutex,
-    time,
+    sync::{broadcast::error::RecvError, mpsc},
+    time::Instant,
 };
 use tokio_stream::wrappers::ReceiverStream;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    backup::BackupHealth,
+    clock::{Clock, Interval},
+    cluster::{Affiliate, AffiliateId, ClusterId, LagPolicy, TalosCluster},
+    encryption::BackupKeys,
+    expiry::ExpiryQueue,
+    journal::{Change, JournalEntry, JournalSync},
+    limits::{Limits, Mode},
+    replication::{
+        replication_client::ReplicationClient, replication_server::Replication, Event, PromoteRequest, PromoteResponse,
+        ReplicationEvent, ReplicationSnapshot, ReplicationToken, Replicator, Source, SubscribeRequest,
+    },
+    snapshot::AffiliateBackup,
+    state::{self, StateBackend, StateStore},
+    store::{ClusterStore, SharedCluster},
+    validation::Validator,
+};
 
-use crate::cluster::{Affiliate, ClusterId, TalosCluster};
+/// Settings of the discovery service.
+#[derive(Clone, Debug)]
+pub struct ServiceOptions {
+    pub gc_interval: Duration,
+    pub state_backend: StateBackend,
+    // directory of the backup file, backups are deactivated without it
+    pub backup_path: Option<PathBuf>,
+    pub backup_interval: Duration,
+    // number of backup files kept, the newest valid one is restored
+    pub backup_generations: usize,
+    // state file of the upstream discovery service, imported without a backup and rewritten with every backup
+    pub upstream_snapshot_path: Option<PathBuf>,
+    // backups are encrypted with these keys, upstream snapshots are always written in plaintext
+    pub backup_keys: Option<BackupKeys>,
+    // changes between backups are journaled next to the backup file
+    pub journal_sync: JournalSync,
+    pub journal_sync_interval: Duration,
+    // URL of the primary's replication service, this instance is a passive standby replicating its state until it's
+    // promoted
+    pub replicate_from: Option<String>,
+    // endpoint clients are redirected to while passive, required for a standby
+    pub primary_endpoint: Option<String>,
+    // URLs of the replication services of the other instances, all of them accept writes and merge their states
+    pub peers: Vec<String>,
+    // presented to the replication services of the primary and the peers, required to replicate from them
+    pub replication_token: Option<ReplicationToken>,
+    pub watch_lag_policy: LagPolicy,
+    pub mode: Mode,
+    pub limits: Limits,
+    // restored affiliates are kept at least this long, giving clients time to re-register
+    pub restore_grace_period: Duration,
+}
+
+impl Default for ServiceOptions {
+    fn default() -> Self {
+        Self {
+            gc_interval: Duration::from_secs(60),
+            state_backend: StateBackend::default(),
+            backup_path: None,
+            backup_interval: Duration::from_secs(600),
+            backup_generations: 3,
+            upstream_snapshot_path: None,
+            backup_keys: None,
+            journal_sync: JournalSync::default(),
+            journal_sync_interval: Duration::from_millis(100),
+            replicate_from: None,
+            primary_endpoint: None,
+            peers: Vec::new(),
+            replication_token: None,
+            watch_lag_policy: LagPolicy::default(),
+            mode: Mode::default(),
+            limits: Limits::default(),
+            restore_grace_period: Mode::default().restore_grace_period(),
+        }
+    }
+}
 
 #[derive(Clone)]
-pub(crate) struct DiscoveryService {
-    clusters: Arc<Mutex<HashMap<ClusterId, TalosCluster>>>,
+pub struct DiscoveryService {
+    clusters: Arc<ClusterStore>,
+    expiry: Arc<ExpiryQueue>,
     gc_interval: Duration,
-    backup_path: Option<PathBuf>,
+    state: Arc<dyn StateStore>,
     backup_interval: Duration,
+    backup_health: Arc<BackupHealth>,
+    replicator: Arc<Replicator>,
+    replication_token: Option<ReplicationToken>,
+    // tells standbys whether this instance restarted since their last snapshot
+    instance_id: String,
+    // deletions are remembered until they can't conflict with older updates of peers anymore
+    has_peers: bool,
+    watch_lag_policy: LagPolicy,
+    mode: Mode,
+    limits: Limits,
+    restore_grace_period: Duration,
+    validator: Validator,
+    clock: Arc<dyn Clock>,
 }
 
 impl DiscoveryService {
-    const BACKUP_FILE_NAME: &str = "discovery_service_backup.json";
-
-    pub async fn new(gc_interval: u16, backup_path: Option<String>, backup_interval: u16) -> anyhow::Result<Self> {
-        let backup_path = backup_path.map(|path| PathBuf::from(path).join(Self::BACKUP_FILE_NAME));
+    const SERVER_MODE_METADATA_KEY: &str = "x-discovery-server-mode";
+    // first delay before retrying a failed backup, doubled for every further failure
+    const BACKUP_RETRY_DELAY: Duration = Duration::from_secs(1);
+    // delay before a standby reconnects to its primary
+    const REPLICATION_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+    pub async fn new(options: ServiceOptions, clock: Arc<dyn Clock>) -> anyhow::Result<Self> {
+        let state = state::open(&options, clock.clone()).await?;
+        // the replication service has its own listener, so the primary's URL doesn't tell where its clients connect to
+        ensure!(
+            options.replicate_from.is_none() || options.primary_endpoint.is_some(),
+            "a standby requires the endpoint of its primary"
+        );
+        ensure!(
+            (options.replicate_from.is_none() && options.peers.is_empty()) || options.replication_token.is_some(),
+            "replicating from a primary or peers requires a replication token"
+        );
+        let primary_endpoint = options.replicate_from.as_ref().and(options.primary_endpoint);
 
         let new = Self {
-            clusters: Arc::new(Mutex::new(HashMap::new())),
-            gc_interval: Duration::from_secs(gc_interval.into()),
-            backup_path,
-            backup_interval: Duration::from_secs(backup_interval.into()),
+            clusters: Arc::new(ClusterStore::new()),
+            expiry: Arc::new(ExpiryQueue::new(clock.clone())),
+            gc_interval: options.gc_interval,
+            state,
+            backup_interval: options.backup_interval,
+            backup_health: Arc::default(),
+            replicator: Arc::new(Replicator::new(primary_endpoint)),
+            replication_token: options.replication_token,
+            instance_id: {
+                let mut instance_id = [0; 16];
+                OsRng.fill_bytes(&mut instance_id);
+                hex::encode(instance_id)
+            },
+            has_peers: !options.peers.is_empty(),
+            watch_lag_policy: options.watch_lag_policy,
+            mode: options.mode,
+            limits: options.limits,
+            restore_grace_period: options.restore_grace_period,
+            validator: Validator::new(options.mode, options.limits),
+            clock,
         };
 
         new.import_backup().await?;
 
         new.run_backup_loop().await;
+        new.run_expiry_loop().await;
         new.run_gc_loop().await;
+        if let Some(primary_url) = options.replicate_from {
+            new.run_replication_loop(primary_url, Source::Primary).await;
+        }
+        for peer_url in options.peers {
+            new.run_replication_loop(peer_url, Source::Peer).await;
+        }
 
         Ok(new)
     }
 
-    async fn get_cluster<'a>(
-        &self,
-        clusters: &'a mut HashMap<ClusterId, TalosCluster>,
-        cluster_id: ClusterId,
-    ) -> Option<&'a mut TalosCluster> {
-        clusters.get_mut(&cluster_id)
+    async fn get_cluster(&self, cluster_id: &ClusterId) -> Option<SharedCluster> {
+        self.clusters.get(cluster_id).await
     }
 
-    async fn get_or_create_cluster<'a>(
-        &self,
-        clusters: &'a mut HashMap<ClusterId, TalosCluster>,
-        cluster_id: ClusterId,
-    ) -> &'a mut TalosCluster {
-        if clusters.contains_key(&cluster_id) {
-            return self.get_cluster(clusters, cluster_id).await.unwrap();
+    async fn get_or_create_cluster(&self, cluster_id: &ClusterId) -> SharedCluster {
+        self.clusters
+            .get_or_insert_with(cluster_id, || {
+                info!("Creating new cluster with ID {}", cluster_id);
+                TalosCluster::new(cluster_id.clone(), self.limits, self.clock.clone())
+            })
+            .await
+    }
+
+    fn schedule_expiry(&self, cluster: &TalosCluster) {
+        if let Some(deadline) = cluster.next_expiration() {
+            self.expiry.schedule(&cluster.id, deadline);
         }
+    }
+
+    async fn run_expiry_loop(&self) {
+        let self_clone = self.clone();
+        tokio::task::spawn(async move {
+            info!("Expiry loop started");
+            loop {
+                for cluster_id in self_clone.expiry.next_due().await {
+                    self_clone.expire_cluster(&cluster_id).await;
+                }
+            }
+        });
+    }
 
-        info!("Creating new cluster with ID {}", cluster_id.clone());
-        clusters.insert(cluster_id.clone(), TalosCluster::new(cluster_id.clone()));
-        self.get_cluster(clusters, cluster_id).await.unwrap()
+    async fn expire_cluster(&self, cluster_id: &ClusterId) {
+        // clusters removed by the GC in the meantime have nothing left to expire
+        let Some(cluster) = self.get_cluster(cluster_id).await else {
+            return;
+        };
+        let mut cluster = cluster.lock().await;
+
+        let expired = cluster.expire(self.clock.now()).await;
+        if expired > 0 {
+            debug!("Expired {} affiliates of cluster {}", expired, cluster_id);
+        }
+        self.schedule_expiry(&cluster);
     }
 
     async fn run_gc_loop(&self) {
         let self_clone = self.clone();
         tokio::task::spawn(async move {
-            let mut gc_interval = time::interval(self_clone.gc_interval);
+            let mut gc_interval = Interval::new(self_clone.clock.clone(), self_clone.gc_interval);
 
             info!("Garbage collector started");
             loop {
@@ -84,71 +237,157 @@ impl DiscoveryService {
     async fn run_gc(&self) {
         debug!("run_gc");
 
-        let mut clusters = self.clusters.lock().await;
-        for cluster in clusters.values_mut() {
+        // each cluster is locked on its own, so requests for other clusters aren't blocked
+        for cluster in self.clusters.clusters().await {
+            let mut cluster = cluster.lock().await;
             cluster.run_gc().await;
+            self.schedule_expiry(&cluster);
+            debug!("{}", cluster.to_string());
         }
 
-        let before_len = clusters.len();
-        clusters.retain(|_, cluster| !cluster.has_affiliates());
+        // clusters with watchers are kept, otherwise the watchers would miss updates of a newly created cluster
+        let (removed, remaining) = self
+            .clusters
+            .retain(|cluster| {
+                cluster.has_affiliates() || cluster.has_watchers() || (self.has_peers && cluster.has_tombstones())
+            })
+            .await;
 
         info!(
             "GC clusters, removed clusters: {}, remaining clusters: {}",
-            before_len - clusters.len(),
-            clusters.len()
+            removed, remaining
         );
 
-        clusters
-            .iter()
-            .for_each(|(_, cluster)| debug!("{}", cluster.to_string()));
+        match self.state.expire(self.clock.wall()).await {
+            Ok(0) => (),
+            Ok(expired) => debug!("Expired {} persisted affiliates", expired),
+            Err(err) => error!("couldn't expire persisted affiliates: {}", err),
+        }
     }
 
     async fn run_backup_loop(&self) {
-        if self.backup_path.is_none() {
+        if !self.state.is_persistent() {
             debug!("Backups deactivated");
             return;
         }
 
         let self_clone = self.clone();
         tokio::task::spawn(async move {
-            let mut backup_interval = time::interval(self_clone.backup_interval);
+            let clock = self_clone.clock.clone();
+            let mut next_backup = clock.now();
 
             info!("Backup loop started");
             loop {
-                backup_interval.tick().await;
-                if let Err(err) = self_clone.export_backup().await {
-                    error!("couldn't save backup: {}", err.to_string());
-                    error!("stopping backup loop");
-                    return;
-                }
+                clock.sleep_until(next_backup).await;
+                next_backup += self_clone.backup_interval;
+                self_clone.backup_with_retries(next_backup).await;
             }
         });
     }
 
-    async fn export_backup(&self) -> anyhow::Result<()> {
-        debug!("export_backup");
+    // replicated changes are applied first, failures are logged and the periodic saves catch up with them
+    async fn persist_update(&self, cluster: &TalosCluster, affiliate_id: &String) -> Result<(), Status> {
+        if !self.state.is_persistent() && !self.replicator.has_subscribers() {
+            return Ok(());
+        }
+        let Some(affiliate) = cluster.affiliate_backup(affiliate_id) else {
+            return Ok(());
+        };
 
-        let backup_path = {
-            match &self.backup_path {
-                Some(backup_path) => backup_path.as_path(),
-                None => return Ok(()),
+        self.publish_update(&cluster.id, affiliate.clone());
+        self.store_update(&cluster.id, affiliate).await
+    }
+
+    async fn persist_deletion(&self, cluster: &TalosCluster, affiliate_id: &str) -> Result<(), Status> {
+        self.publish_deletion(&cluster.id, affiliate_id, cluster.tombstone(affiliate_id));
+        self.store_deletion(&cluster.id, affiliate_id).await
+    }
+
+    // every instance evicts affiliates on its own, the deletions carry no time, so peers don't merge them
+    async fn persist_evictions(&self, cluster: &TalosCluster, evicted: Vec<AffiliateId>) {
+        for affiliate_id in evicted {
+            let _ = self.persist_deletion(cluster, &affiliate_id).await;
+        }
+    }
+
+    // passes a change on to standbys and peers, under the cluster lock, so they get the changes in order
+    fn publish_update(&self, cluster_id: &ClusterId, affiliate: AffiliateBackup) {
+        self.replicator.publish(JournalEntry {
+            cluster_id: cluster_id.clone(),
+            change: Some(Change::Update(affiliate)),
+            deleted: None,
+        });
+    }
+
+    fn publish_deletion(&self, cluster_id: &ClusterId, affiliate_id: &str, deleted: Option<SystemTime>) {
+        self.replicator.publish(JournalEntry {
+            cluster_id: cluster_id.clone(),
+            change: Some(Change::Delete(affiliate_id.to_string())),
+            deleted: deleted.map(Into::into),
+        });
+    }
+
+    async fn store_update(&self, cluster_id: &ClusterId, affiliate: AffiliateBackup) -> Result<(), Status> {
+        if !self.state.is_persistent() {
+            return Ok(());
+        }
+
+        self.state
+            .upsert_affiliate(cluster_id, affiliate)
+            .await
+            .map_err(|err| Status::unavailable(format!("couldn't persist update: {err}")))
+            .inspect_err(|err| error!("{}", err.to_string()))
+    }
+
+    async fn store_deletion(&self, cluster_id: &ClusterId, affiliate_id: &str) -> Result<(), Status> {
+        if !self.state.is_persistent() {
+            return Ok(());
+        }
+
+        self.state
+            .delete_affiliate(cluster_id, affiliate_id)
+            .await
+            .map_err(|err| Status::unavailable(format!("couldn't persist deletion: {err}")))
+            .inspect_err(|err| error!("{}", err.to_string()))
+    }
+
+    // retries with exponential backoff, but at most until the next regular backup
+    async fn backup_with_retries(&self, next_backup: Instant) {
+        let mut delay = Self::BACKUP_RETRY_DELAY;
+
+        loop {
+            let err = match self.export_backup().await {
+                Ok(()) => return self.backup_health.succeeded(),
+                Err(err) => err,
+            };
+
+            let failures = self.backup_health.failed();
+            let retry = self.clock.now() + delay;
+            if retry >= next_backup {
+                error!("couldn't save backup ({} consecutive failures): {}", failures, err);
+                return;
             }
-        };
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(backup_path)
-            .await?;
 
-        let svc_clusters = self.clusters.lock().await;
-        let svc_clusters = svc_clusters.values().collect::<Vec<_>>();
+            error!(
+                "couldn't save backup ({} consecutive failures), retrying in {}s: {}",
+                failures,
+                delay.as_secs(),
+                err
+            );
+            self.clock.sleep_until(retry).await;
+            delay *= 2;
+        }
+    }
 
-        let json = serde_json::to_string(&svc_clusters)?;
-        file.write_all(json.as_bytes()).await?;
-        file.write_u8(b'\n').await?;
+    pub fn backup_health(&self) -> Arc<BackupHealth> {
+        self.backup_health.clone()
+    }
 
-        debug!("{} clusters backed up", svc_clusters.len());
+    async fn export_backup(&self) -> anyhow::Result<()> {
+        debug!("export_backup");
+
+        let clusters = self.state.save(&self.clusters).await?;
+        debug!("{} clusters backed up", clusters);
 
         Ok(())
     }
@@ -156,42 +395,273 @@ impl DiscoveryService {
     async fn import_backup(&self) -> anyhow::Result<()> {
         debug!("import_backup");
 
-        let backup_path = {
-            match &self.backup_path {
-                Some(backup_path) if backup_path.exists() => backup_path.as_path(),
-                _ => return Ok(()),
+        let Some(snapshot) = self.state.load().await? else {
+            return Ok(());
+        };
+
+        let base = match snapshot.snapshot_time {
+            Some(snapshot_time) => self.clock.time_base().restore(snapshot_time, self.limits.max_ttl),
+            None => {
+                warn!("Backup has no snapshot time, restoring expirations relative to the current system clock");
+                self.clock.time_base()
             }
         };
-        let file = File::open(backup_path).await?.into_std().await;
-        let reader = std::io::BufReader::new(file);
+        info!("{} clusters restored", snapshot.clusters.len());
+
+        for cluster in snapshot.clusters {
+            let mut cluster = TalosCluster::from_backup(cluster, &base, self.limits, self.clock.clone());
+            if !self.state.is_exact() {
+                cluster.start_grace_period(self.restore_grace_period);
+            }
+            self.schedule_expiry(&cluster);
+            self.clusters.insert(cluster).await;
+        }
+
+        Ok(())
+    }
+
+    // a passive standby replicates the state of its primary until it's promoted, peers replicate each other's states
+    // for good, both reconnect after errors
+    async fn run_replication_loop(&self, url: String, source: Source) {
+        let self_clone = self.clone();
+        tokio::task::spawn(async move {
+            let mut promoted = std::pin::pin!(async {
+                match source {
+                    Source::Primary => self_clone.replicator.promoted().await,
+                    Source::Peer => std::future::pending().await,
+                }
+            });
+
+            info!("Replicating from {} {}", source, url);
+            let mut primary_instance = None;
+            loop {
+                tokio::select! {
+                    result = self_clone.replicate(&url, source, &mut primary_instance) => match result {
+                        Ok(()) => warn!("Replication stream of {} {} ended", source, url),
+                        Err(err) => error!("couldn't replicate from {} {}: {}", source, url, err),
+                    },
+                    _ = &mut promoted => break,
+                }
+
+                let retry = self_clone.clock.now() + Self::REPLICATION_RETRY_DELAY;
+                tokio::select! {
+                    _ = self_clone.clock.sleep_until(retry) => (),
+                    _ = &mut promoted => break,
+                }
+            }
+            info!("Promoted to primary, replication stopped");
+        });
+    }
 
-        let clusters: Vec<TalosCluster> = serde_json::from_reader(reader)?;
-        info!("{} clusters restored", clusters.len());
+    async fn replicate(
+        &self,
+        url: &str,
+        source: Source,
+        primary_instance: &mut Option<(String, Instant)>,
+    ) -> anyhow::Result<()> {
+        let Some(token) = &self.replication_token else {
+            bail!("no replication token configured");
+        };
+        let mut client = ReplicationClient::connect(url.to_string()).await?;
+        let mut events = client.subscribe(token.request(SubscribeRequest {})).await?.into_inner();
 
-        let mut svc_clusters = self.clusters.lock().await;
-        for cluster in clusters {
-            svc_clusters.insert(cluster.id.clone(), cluster);
+        while let Some(event) = events.message().await? {
+            match (event.event, source) {
+                (Some(Event::Snapshot(snapshot)), Source::Primary) => {
+                    self.apply_snapshot(snapshot, primary_instance).await
+                }
+                (Some(Event::Snapshot(snapshot)), Source::Peer) => self.merge_snapshot(snapshot).await,
+                (Some(Event::Change(entry)), Source::Primary) => self.apply_change(entry).await,
+                (Some(Event::Change(entry)), Source::Peer) => self.merge_change(entry).await,
+                (None, _) => (),
+            }
         }
 
         Ok(())
     }
 
+    // every connection starts with a snapshot, which replaces whatever was replicated before. A primary without
+    // persistence which restarted has lost its state though, its clients only re-register within their TTL. Until
+    // then its snapshots are merged, so the standby doesn't drop the affiliates which are missing on the primary
+    async fn apply_snapshot(&self, snapshot: ReplicationSnapshot, primary_instance: &mut Option<(String, Instant)>) {
+        let now = self.clock.now();
+        let first_seen = match primary_instance {
+            Some((instance_id, first_seen)) if *instance_id == snapshot.instance_id => *first_seen,
+            _ => now,
+        };
+        *primary_instance = Some((snapshot.instance_id, first_seen));
+
+        let clusters = snapshot.clusters;
+        if !snapshot.persistent && now < first_seen + self.limits.max_ttl {
+            info!(
+                "Merging {} clusters of a primary without persistence, which may have restarted",
+                clusters.len()
+            );
+            for backup in clusters {
+                let cluster = self.get_or_create_cluster(&backup.id).await;
+                let mut cluster = cluster.lock().await;
+                for affiliate in backup.affiliates {
+                    let affiliate_id = affiliate.id.clone();
+                    if let Some(evicted) = cluster.apply_affiliate(affiliate).await {
+                        self.persist_evictions(&cluster, evicted).await;
+                        let _ = self.persist_update(&cluster, &affiliate_id).await;
+                    }
+                }
+                self.schedule_expiry(&cluster);
+            }
+            return;
+        }
+
+        info!("Replicating {} clusters of the primary", clusters.len());
+
+        let replicated = clusters
+            .iter()
+            .map(|cluster| cluster.id.clone())
+            .collect::<HashSet<_>>();
+        for backup in clusters {
+            let cluster = self.get_or_create_cluster(&backup.id).await;
+            self.replace_affiliates(&mut *cluster.lock().await, backup.affiliates)
+                .await;
+        }
+
+        for cluster in self.clusters.clusters().await {
+            let mut cluster = cluster.lock().await;
+            if !replicated.contains(&cluster.id) {
+                self.replace_affiliates(&mut cluster, Vec::new()).await;
+            }
+        }
+    }
+
+    async fn replace_affiliates(&self, cluster: &mut TalosCluster, affiliates: Vec<AffiliateBackup>) {
+        let updated = affiliates
+            .iter()
+            .map(|affiliate| affiliate.id.clone())
+            .collect::<Vec<_>>();
+
+        // failures are logged, the periodic saves catch up with the replicated state
+        for affiliate_id in cluster.replace_affiliates(affiliates).await {
+            let _ = self.persist_deletion(cluster, &affiliate_id).await;
+        }
+        for affiliate_id in &updated {
+            let _ = self.persist_update(cluster, affiliate_id).await;
+        }
+        self.schedule_expiry(cluster);
+    }
+
+    async fn apply_change(&self, entry: JournalEntry) {
+        let cluster = self.get_or_create_cluster(&entry.cluster_id).await;
+        let mut cluster = cluster.lock().await;
+
+        match entry.change {
+            Some(Change::Update(affiliate)) => {
+                let affiliate_id = affiliate.id.clone();
+                if let Some(evicted) = cluster.apply_affiliate(affiliate).await {
+                    self.schedule_expiry(&cluster);
+                    self.persist_evictions(&cluster, evicted).await;
+                    let _ = self.persist_update(&cluster, &affiliate_id).await;
+                }
+            }
+            Some(Change::Delete(affiliate_id)) => {
+                cluster.delete_affiliate(&affiliate_id).await;
+                let _ = self.persist_deletion(&cluster, &affiliate_id).await;
+            }
+            None => (),
+        }
+    }
+
+    // a snapshot of a peer is merged like its single changes, so local changes it doesn't know yet are kept
+    async fn merge_snapshot(&self, snapshot: ReplicationSnapshot) {
+        info!("Merging {} clusters of a peer", snapshot.clusters.len());
+
+        let updates = snapshot.clusters.into_iter().flat_map(|cluster| {
+            let cluster_id = cluster.id;
+            cluster.affiliates.into_iter().map(move |affiliate| JournalEntry {
+                cluster_id: cluster_id.clone(),
+                change: Some(Change::Update(affiliate)),
+                deleted: None,
+            })
+        });
+        for entry in updates.chain(snapshot.deletions) {
+            self.merge_change(entry).await;
+        }
+    }
+
+    // changes newer than the local state are passed on, so they reach peers which aren't connected to their origin
+    async fn merge_change(&self, entry: JournalEntry) {
+        let cluster = self.get_or_create_cluster(&entry.cluster_id).await;
+        let mut cluster = cluster.lock().await;
+
+        match entry.change {
+            Some(Change::Update(affiliate)) => {
+                let affiliate_id = affiliate.id.clone();
+                if let Some(evicted) = cluster.merge_affiliate(affiliate).await {
+                    self.schedule_expiry(&cluster);
+                    self.persist_evictions(&cluster, evicted).await;
+                    let _ = self.persist_update(&cluster, &affiliate_id).await;
+                }
+            }
+            // deletions without a time can't be ordered against updates, the affiliate expires on its own instead
+            Some(Change::Delete(affiliate_id)) => {
+                let Some(deleted) = entry.deleted.and_then(|deleted| SystemTime::try_from(deleted).ok()) else {
+                    return;
+                };
+                if cluster.merge_deletion(&affiliate_id, deleted).await {
+                    let _ = self.persist_deletion(&cluster, &affiliate_id).await;
+                }
+            }
+            None => (),
+        }
+    }
+
+    async fn replication_snapshot(&self) -> ReplicationSnapshot {
+        let base = self.clock.time_base();
+        let mut snapshot = ReplicationSnapshot {
+            instance_id: self.instance_id.clone(),
+            persistent: self.state.is_persistent(),
+            ..Default::default()
+        };
+        for cluster in self.clusters.clusters().await {
+            let cluster = cluster.lock().await;
+            snapshot.clusters.push(cluster.backup(&base));
+            snapshot
+                .deletions
+                .extend(cluster.tombstones().map(|(affiliate_id, deleted)| JournalEntry {
+                    cluster_id: cluster.id.clone(),
+                    change: Some(Change::Delete(affiliate_id.clone())),
+                    deleted: Some(deleted.into()),
+                }));
+        }
+        snapshot
+    }
+
+    // a passive standby only serves reads, the Hello response redirects clients to the primary
+    async fn check_writable(&self) -> Result<(), Status> {
+        match self.replicator.primary() {
+            Some(primary) => Err(Status::unavailable(format!(
+                "passive standby, writes go to the primary {primary}"
+            )))
+            .inspect_err(|err| debug!("{}", err.to_string())),
+            None => Ok(()),
+        }
+    }
+
     async fn update_clusters(
         &self,
         request: AffiliateUpdateRequest,
     ) -> Result<Response<AffiliateUpdateResponse>, Status> {
-        let mut clusters = self.clusters.lock().await;
-        let cluster_id = request.cluster_id.clone();
-
-        match clusters.get_mut(&cluster_id) {
-            Some(existing_cluster) => existing_cluster.add_affiliate(&request).await?,
-            None => {
-                info!("Creating new cluster with ID {}", cluster_id.clone());
-                let mut cluster = TalosCluster::new(cluster_id.clone());
-                cluster.add_affiliate(&request).await?;
-                clusters.insert(cluster_id, cluster);
-            }
-        };
+        // a new cluster which stays empty because of a rejected update is removed by the next GC
+        let cluster = self.get_or_create_cluster(&request.cluster_id).await;
+        let mut cluster = cluster.lock().await;
+        let affiliate = cluster.prepare_affiliate(&request).await?;
+
+        // persisted before it's applied, so a failed write isn't visible to anyone
+        let backup = affiliate.backup(&self.clock.time_base());
+        self.store_update(&cluster.id, backup.clone()).await?;
+        cluster.commit_affiliate(affiliate).await;
+        self.schedule_expiry(&cluster);
+        if self.replicator.has_subscribers() {
+            self.publish_update(&cluster.id, backup);
+        }
 
         Ok(Response::new(AffiliateUpdateResponse {}))
     }
@@ -209,32 +679,38 @@ impl Cluster for DiscoveryService {
             .ok_or(Status::invalid_argument("Couldn't parse IP address"))
             .inspect_err(|err| debug!("{}", err.to_string()))?;
 
+        self.validator.hello(request.get_ref())?;
+
         let ip = match socket.ip() {
             IpAddr::V4(ipv4) => ipv4.octets().to_vec(),
             IpAddr::V6(ipv6) => ipv6.octets().to_vec(),
         };
 
-        Ok(Response::new(HelloResponse {
-            redirect: None,
+        let mut response = Response::new(HelloResponse {
+            redirect: self.replicator.primary().map(|primary| RedirectMessage {
+                endpoints: vec![primary],
+            }),
             client_ip: ip,
-        }))
+        });
+        response.metadata_mut().insert(
+            Self::SERVER_MODE_METADATA_KEY,
+            MetadataValue::from_static(self.mode.as_str()),
+        );
+
+        Ok(response)
     }
 
     async fn watch(&self, request: Request<WatchRequest>) -> Result<Response<Self::WatchStream>, Status> {
         info!("Cluster node request: Watch ({})", request.remote_addr().unwrap().ip());
 
         let request = request.into_inner();
-        let cluster_id = request.cluster_id;
+        self.validator.watch(&request)?;
 
-        // XXX: custom extension
-        if cluster_id.len() > TalosCluster::MAX_IDENTIFIER_LENGTH {
-            return Err(Status::invalid_argument("maximum identifier length exceeded"));
-        }
+        let cluster_id = request.cluster_id;
 
-        let mut clusters = self.clusters.lock().await;
-        let cluster = self.get_or_create_cluster(&mut clusters, cluster_id.clone()).await;
+        let cluster = self.get_or_create_cluster(&cluster_id).await;
 
-        let watch_stream = cluster.subscribe().await;
+        let watch_stream = TalosCluster::subscribe(&cluster, self.watch_lag_policy).await;
 
         Ok(Response::new(ReceiverStream::new(watch_stream)))
     }
@@ -250,33 +726,8 @@ impl Cluster for DiscoveryService {
 
         let request = request.into_inner();
 
-        // XXX: custom extension
-        if request.cluster_id.len() > TalosCluster::MAX_IDENTIFIER_LENGTH
-            || request.affiliate_id.len() > TalosCluster::MAX_IDENTIFIER_LENGTH
-        {
-            return Err(Status::invalid_argument("maximum identifier length exceeded"));
-        }
-
-        // XXX: custom extension
-        if let Some(affiliate_data) = &request.affiliate_data {
-            if affiliate_data.len() > TalosCluster::MAX_PAYLOAD_LENGTH {
-                return Err(Status::invalid_argument("maximum payload length exceeded"));
-            }
-        }
-
-        // XXX: custom extension
-        for endpoint in &request.affiliate_endpoints {
-            if endpoint.len() > TalosCluster::MAX_PAYLOAD_LENGTH {
-                return Err(Status::invalid_argument("maximum payload length exceeded"));
-            }
-        }
-
-        // XXX: custom extension
-        if let Some(ttl) = &request.ttl {
-            if ttl.seconds <= 0 || ttl.seconds as u64 > TalosCluster::MAX_TTL_DURATION.as_secs() {
-                return Err(Status::invalid_argument("maximum TTL exceeded"));
-            }
-        }
+        self.check_writable().await?;
+        self.validator.affiliate_update(&request)?;
 
         self.update_clusters(request).await
     }
@@ -291,20 +742,30 @@ impl Cluster for DiscoveryService {
         );
 
         let request = request.into_inner();
+        self.check_writable().await?;
+        self.validator.affiliate_delete(&request)?;
+
         let cluster_id = request.cluster_id;
         let affiliate_id = request.affiliate_id;
 
-        let mut clusters = self.clusters.lock().await;
-        let cluster = self
-            .get_cluster(&mut clusters, cluster_id.clone())
-            .await
-            .ok_or(Status::not_found(format!("Cluster ID {cluster_id} not found")))
-            .inspect_err(|err| error!("{}", err.to_string()))?;
+        let cluster = match self.get_cluster(&cluster_id).await {
+            Some(cluster) => cluster,
+            // upstream treats unknown clusters like empty ones
+            None if self.mode == Mode::Compat => return Ok(Response::new(AffiliateDeleteResponse {})),
+            None => {
+                return Err(Status::not_found(format!("Cluster ID {cluster_id} not found")))
+                    .inspect_err(|err| error!("{}", err.to_string()))
+            }
+        };
 
+        let mut cluster = cluster.lock().await;
         match cluster.get_affiliate(&affiliate_id).await {
             Some(_) => {
-                cluster.delete_affiliate(&affiliate_id).await;
-                cluster.broadcast_affiliate_states().await;
+                // persisted before it's applied, so a failed write isn't visible to anyone
+                let deleted = cluster.write_time(&affiliate_id);
+                self.store_deletion(&cluster.id, &affiliate_id).await?;
+                cluster.delete_affiliate_at(&affiliate_id, deleted).await;
+                self.publish_deletion(&cluster.id, &affiliate_id, Some(deleted));
 
                 info!("Deleted affiliate ID {} from cluster {}", affiliate_id, cluster_id);
             }
@@ -318,23 +779,879 @@ impl Cluster for DiscoveryService {
         info!("Cluster node request: List ({})", request.remote_addr().unwrap().ip());
 
         let request = request.into_inner();
+        self.validator.list(&request)?;
+
         let cluster_id = request.cluster_id;
 
-        let mut clusters = self.clusters.lock().await;
-        let cluster = self
-            .get_cluster(&mut clusters, cluster_id.clone())
-            .await
-            .ok_or(Status::not_found(format!("Cluster ID {cluster_id} not found")))
-            .inspect_err(|err| error!("{}", err.to_string()))?;
+        let cluster = match self.get_cluster(&cluster_id).await {
+            Some(cluster) => cluster,
+            // upstream treats unknown clusters like empty ones
+            None if self.mode == Mode::Compat => return Ok(Response::new(ListResponse { affiliates: Vec::new() })),
+            None => {
+                return Err(Status::not_found(format!("Cluster ID {cluster_id} not found")))
+                    .inspect_err(|err| error!("{}", err.to_string()))
+            }
+        };
 
         let affiliates = cluster
+            .lock()
+            .await
             .get_affiliates()
             .await
             .into_iter()
-            .cloned()
             .map(Affiliate::into)
             .collect::<Vec<discovery_api::Affiliate>>();
 
         Ok(Response::new(ListResponse { affiliates }))
     }
 }
+
+#[async_trait]
+impl Replication for DiscoveryService {
+    type SubscribeStream = ReceiverStream<Result<ReplicationEvent, Status>>;
+
+    async fn subscribe(&self, request: Request<SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        info!("Standby request: Subscribe ({})", request.remote_addr().unwrap().ip());
+
+        // changes made while the snapshot is taken are sent after it, applying them again does no harm
+        let mut changes = self.replicator.subscribe();
+        let snapshot = self.replication_snapshot().await;
+
+        let (tx, rx) = mpsc::channel(self.limits.buffer_size);
+        tokio::spawn(async move {
+            let event = |event| ReplicationEvent { event: Some(event) };
+            if tx.send(Ok(event(Event::Snapshot(snapshot)))).await.is_err() {
+                return;
+            }
+
+            loop {
+                let change = tokio::select! {
+                    change = changes.recv() => change,
+                    _ = tx.closed() => return,
+                };
+
+                match change {
+                    Ok(entry) => {
+                        if tx.send(Ok(event(Event::Change(entry)))).await.is_err() {
+                            return;
+                        }
+                    }
+                    // the standby reconnects and starts over with a new snapshot
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!(
+                            "Standby fell behind by {} changes, closing its replication stream",
+                            missed
+                        );
+                        let status = Status::resource_exhausted(format!("standby fell behind by {missed} changes"));
+                        let _ = tx.send(Err(status)).await;
+                        return;
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn promote(&self, request: Request<PromoteRequest>) -> Result<Response<PromoteResponse>, Status> {
+        info!("Standby request: Promote ({})", request.remote_addr().unwrap().ip());
+
+        if self.replicator.promote() {
+            info!("Promoted to primary, accepting writes");
+        } else {
+            debug!("Already accepting writes");
+        }
+
+        Ok(Response::new(PromoteResponse {}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::ManualClock,
+        encryption,
+        file_state::FileStore,
+        fixtures::{temp_dir, update_request},
+        replication::replication_server::ReplicationServer,
+        snapshot::{self, AffiliateBackup, ClusterBackup, Snapshot},
+    };
+    use discovery_api::{
+        cluster_client::ClusterClient,
+        cluster_server::ClusterServer,
+        tonic::{transport::Server, Code},
+    };
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    const GC_INTERVAL: Duration = Duration::from_secs(60);
+    const BACKUP_INTERVAL: Duration = Duration::from_secs(600);
+
+    async fn service(backup_dir: Option<&std::path::Path>, clock: &Arc<ManualClock>) -> DiscoveryService {
+        let options = ServiceOptions {
+            gc_interval: GC_INTERVAL,
+            backup_path: backup_dir.map(std::path::Path::to_path_buf),
+            backup_interval: BACKUP_INTERVAL,
+            restore_grace_period: Duration::ZERO,
+            ..Default::default()
+        };
+        DiscoveryService::new(options, clock.clone()).await.unwrap()
+    }
+
+    async fn watch(service: &DiscoveryService) -> tokio::sync::mpsc::Receiver<Result<WatchResponse, Status>> {
+        let cluster = service.get_or_create_cluster(&"cluster".to_string()).await;
+        TalosCluster::subscribe(&cluster, LagPolicy::Resync).await
+    }
+
+    async fn remaining_ttl(service: &DiscoveryService, clock: &ManualClock) -> Duration {
+        let cluster = service
+            .get_cluster(&"cluster".to_string())
+            .await
+            .expect("cluster restored");
+        let deadline = cluster.lock().await.next_expiration().expect("affiliate restored");
+        deadline - clock.now()
+    }
+
+    #[tokio::test]
+    async fn gc_keeps_watched_clusters() {
+        let clock = ManualClock::new();
+        let service = service(None, &clock).await;
+
+        let mut watcher = watch(&service).await;
+        assert!(watcher.recv().await.unwrap().unwrap().affiliates.is_empty());
+
+        service.run_gc().await;
+
+        service.update_clusters(update_request(60)).await.unwrap();
+
+        let update = watcher.recv().await.unwrap().unwrap();
+        assert_eq!(update.affiliates[0].id, "affiliate");
+    }
+
+    #[tokio::test]
+    async fn gc_removes_unused_clusters_on_schedule() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let service = service(None, &clock).await;
+        clock.sleeping_until(start + GC_INTERVAL).await;
+
+        service.get_or_create_cluster(&"cluster".to_string()).await;
+        assert!(service.get_cluster(&"cluster".to_string()).await.is_some());
+
+        clock.advance(GC_INTERVAL);
+        clock.sleeping_until(start + GC_INTERVAL * 2).await;
+        assert!(service.get_cluster(&"cluster".to_string()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn affiliates_expire_at_their_deadline_without_gc() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let service = service(None, &clock).await;
+
+        let mut watcher = watch(&service).await;
+        watcher.recv().await.unwrap().unwrap();
+
+        // expires between two GC runs
+        service.update_clusters(update_request(90)).await.unwrap();
+        assert!(!watcher.recv().await.unwrap().unwrap().deleted);
+        clock.sleeping_until(start + Duration::from_secs(90)).await;
+
+        clock.advance(Duration::from_secs(89));
+        clock.sleeping_until(start + GC_INTERVAL * 2).await;
+        assert!(watcher.try_recv().is_err());
+
+        clock.advance(Duration::from_secs(1));
+        let event = watcher.recv().await.unwrap().unwrap();
+        assert!(event.deleted);
+        assert_eq!(event.affiliates[0].id, "affiliate");
+        assert_eq!(clock.now(), start + Duration::from_secs(90));
+    }
+
+    #[tokio::test]
+    async fn backups_are_written_on_schedule() {
+        let dir = temp_dir();
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let service = service(Some(dir.path()), &clock).await;
+
+        let read_backup =
+            || snapshot::decode(&std::fs::read(dir.path().join(FileStore::BACKUP_FILE_NAME)).unwrap()).unwrap();
+
+        clock.sleeping_until(start + BACKUP_INTERVAL).await;
+        assert!(read_backup().clusters.is_empty());
+
+        service.update_clusters(update_request(3600)).await.unwrap();
+        clock.advance(BACKUP_INTERVAL - Duration::from_secs(1));
+        assert!(read_backup().clusters.is_empty());
+
+        clock.advance(Duration::from_secs(1));
+        clock.sleeping_until(start + BACKUP_INTERVAL * 2).await;
+        let backup = read_backup();
+        assert_eq!(backup.clusters.len(), 1);
+        assert_eq!(backup.snapshot_time, Some(clock.wall()));
+    }
+
+    #[tokio::test]
+    async fn memory_backend_persists_nothing() {
+        let dir = temp_dir();
+        let clock = ManualClock::new();
+        let options = ServiceOptions {
+            state_backend: StateBackend::Memory,
+            backup_path: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let service = DiscoveryService::new(options, clock.clone()).await.unwrap();
+
+        service.update_clusters(update_request(60)).await.unwrap();
+        service.export_backup().await.unwrap();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn redb_backend_restores_every_change() {
+        let dir = temp_dir();
+        let clock = ManualClock::new();
+        let options = ServiceOptions {
+            state_backend: StateBackend::Redb,
+            backup_path: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+
+        // the database is only closed once the background tasks of the first service end with its runtime
+        let first_run = (options.clone(), clock.clone());
+        tokio::task::spawn_blocking(move || {
+            let (options, clock) = first_run;
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async {
+                let service = DiscoveryService::new(options, clock).await.unwrap();
+                // no backup is taken before the restart
+                service.update_clusters(update_request(60)).await.unwrap();
+            });
+        })
+        .await
+        .unwrap();
+        clock.advance(Duration::from_secs(20));
+
+        let restored = DiscoveryService::new(options, clock.clone()).await.unwrap();
+        assert_eq!(remaining_ttl(&restored, &clock).await, Duration::from_secs(40));
+
+        // the database holds the exact state, so the restored affiliates aren't kept around for a grace period
+        clock.advance(Duration::from_secs(40));
+        let cluster = restored.get_cluster(&"cluster".to_string()).await.unwrap();
+        let mut cluster = cluster.lock().await;
+        cluster.expire(clock.now()).await;
+        assert!(!cluster.has_affiliates());
+    }
+
+    #[tokio::test]
+    async fn backup_restores_remaining_ttl() {
+        let dir = temp_dir();
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let service = service(Some(dir.path()), &clock).await;
+        clock.sleeping_until(start + BACKUP_INTERVAL).await;
+
+        service.update_clusters(update_request(60)).await.unwrap();
+        service.export_backup().await.unwrap();
+
+        clock.advance(Duration::from_secs(20));
+        let restored = self::service(Some(dir.path()), &clock).await;
+        assert_eq!(remaining_ttl(&restored, &clock).await, Duration::from_secs(40));
+
+        let affiliates = restored.get_cluster(&"cluster".to_string()).await.unwrap();
+        let affiliates = affiliates.lock().await.get_affiliates().await;
+        let affiliate = discovery_api::Affiliate::from(affiliates[0].clone());
+        assert_eq!(affiliate.endpoints, vec![b"endpoint".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn journal_restores_changes_since_last_backup() {
+        let dir = temp_dir();
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let options = ServiceOptions {
+            backup_path: Some(dir.path().to_path_buf()),
+            backup_interval: BACKUP_INTERVAL,
+            journal_sync: JournalSync::Always,
+            restore_grace_period: Duration::ZERO,
+            ..Default::default()
+        };
+        let service = DiscoveryService::new(options.clone(), clock.clone()).await.unwrap();
+        clock.sleeping_until(start + BACKUP_INTERVAL).await;
+
+        // changed after the last backup, before a crash
+        service.update_clusters(update_request(60)).await.unwrap();
+        clock.advance(Duration::from_secs(20));
+
+        let restarted = clock.now();
+        let restored = DiscoveryService::new(options, clock.clone()).await.unwrap();
+        assert_eq!(remaining_ttl(&restored, &clock).await, Duration::from_secs(40));
+        clock.sleeping_until(restarted + BACKUP_INTERVAL).await;
+
+        let journal_segments = || {
+            std::fs::read_dir(dir.path())
+                .unwrap()
+                .filter(|entry| {
+                    let file_name = entry.as_ref().unwrap().file_name();
+                    file_name.to_string_lossy().starts_with(FileStore::JOURNAL_FILE_NAME)
+                })
+                .count()
+        };
+        // the replayed changes are part of the next backup
+        assert_eq!(journal_segments(), 1);
+    }
+
+    #[tokio::test]
+    async fn encrypted_backup_requires_the_key() {
+        let dir = temp_dir();
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let options = |keys: &str| ServiceOptions {
+            backup_path: Some(dir.path().to_path_buf()),
+            backup_interval: BACKUP_INTERVAL,
+            backup_keys: Some(keys.parse().unwrap()),
+            restore_grace_period: Duration::ZERO,
+            ..Default::default()
+        };
+        let key = "00".repeat(32);
+        let new_key = "11".repeat(32);
+
+        let service = DiscoveryService::new(options(&key), clock.clone()).await.unwrap();
+        clock.sleeping_until(start + BACKUP_INTERVAL).await;
+        service.update_clusters(update_request(60)).await.unwrap();
+        service.export_backup().await.unwrap();
+
+        let contents = std::fs::read(dir.path().join(FileStore::BACKUP_FILE_NAME)).unwrap();
+        assert!(encryption::is_encrypted(&contents));
+
+        let err = DiscoveryService::new(options(&new_key), clock.clone())
+            .await
+            .err()
+            .unwrap();
+        assert!(format!("{err:#}").contains("key is wrong"));
+        let unencrypted = ServiceOptions {
+            backup_keys: None,
+            ..options(&key)
+        };
+        let err = DiscoveryService::new(unencrypted, clock.clone()).await.err().unwrap();
+        assert!(format!("{err:#}").contains("no backup key is configured"));
+
+        let restored = DiscoveryService::new(options(&format!("{new_key},{key}")), clock.clone())
+            .await
+            .unwrap();
+        assert_eq!(remaining_ttl(&restored, &clock).await, Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn legacy_backup_is_restored() {
+        let dir = temp_dir();
+        let clock = ManualClock::new();
+        let legacy = serde_json::json!([{
+            "id": "cluster",
+            "affiliates": {
+                "affiliate": {
+                    "id": "affiliate",
+                    "data": [1, 2, 3],
+                    "endpoints": [],
+                    "expiration": clock.wall() + Duration::from_secs(60),
+                }
+            }
+        }]);
+        std::fs::write(dir.path().join(FileStore::LEGACY_BACKUP_FILE_NAME), legacy.to_string()).unwrap();
+
+        let restored = service(Some(dir.path()), &clock).await;
+        assert_eq!(remaining_ttl(&restored, &clock).await, Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn upstream_snapshot_is_imported_and_exported() {
+        let dir = temp_dir();
+        let clock = ManualClock::new();
+        let upstream_path = dir.path().join("state.binpb");
+        let upstream = Snapshot {
+            snapshot_time: None,
+            journal_generation: 0,
+            clusters: vec![ClusterBackup {
+                id: "cluster".to_string(),
+                affiliates: vec![AffiliateBackup {
+                    id: "affiliate".to_string(),
+                    data: b"data".to_vec(),
+                    endpoints: Vec::new(),
+                    expiration: Some((clock.wall() + Duration::from_secs(60)).into()),
+                    updated: None,
+                }],
+            }],
+        };
+        std::fs::write(&upstream_path, snapshot::encode_upstream(&upstream).unwrap()).unwrap();
+
+        let options = ServiceOptions {
+            backup_path: Some(dir.path().to_path_buf()),
+            upstream_snapshot_path: Some(upstream_path.clone()),
+            backup_interval: BACKUP_INTERVAL,
+            restore_grace_period: Duration::ZERO,
+            ..Default::default()
+        };
+        let start = clock.now();
+        let restored = DiscoveryService::new(options, clock.clone()).await.unwrap();
+        assert_eq!(remaining_ttl(&restored, &clock).await, Duration::from_secs(60));
+        clock.sleeping_until(start + BACKUP_INTERVAL).await;
+
+        restored.update_clusters(update_request(3600)).await.unwrap();
+        restored.export_backup().await.unwrap();
+        let exported = snapshot::decode_upstream(&std::fs::read(&upstream_path).unwrap()).unwrap();
+        assert_eq!(exported.clusters[0].affiliates[0].data, b"data".to_vec());
+        assert!(dir.path().join(FileStore::BACKUP_FILE_NAME).exists());
+    }
+
+    #[tokio::test]
+    async fn restored_affiliates_outlive_downtime_during_grace_period() {
+        let dir = temp_dir();
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let service = service(Some(dir.path()), &clock).await;
+        clock.sleeping_until(start + BACKUP_INTERVAL).await;
+
+        service.update_clusters(update_request(60)).await.unwrap();
+        service.export_backup().await.unwrap();
+
+        // stays clear of the next backup of the first service
+        clock.advance(BACKUP_INTERVAL - Duration::from_secs(1));
+        let options = ServiceOptions {
+            backup_path: Some(dir.path().to_path_buf()),
+            restore_grace_period: Duration::from_secs(120),
+            ..Default::default()
+        };
+        let restored = DiscoveryService::new(options, clock.clone()).await.unwrap();
+        // the already expired affiliate is held back until the end of the grace period
+        clock.sleeping_until(clock.now() + Duration::from_secs(120)).await;
+        assert_eq!(remaining_ttl(&restored, &clock).await, Duration::from_secs(120));
+
+        let mut watcher = watch(&restored).await;
+        assert_eq!(watcher.recv().await.unwrap().unwrap().affiliates.len(), 1);
+
+        clock.advance(Duration::from_secs(120));
+        assert!(watcher.recv().await.unwrap().unwrap().deleted);
+    }
+
+    #[tokio::test]
+    async fn truncated_backup_falls_back_to_previous_generation() {
+        let dir = temp_dir();
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let service = service(Some(dir.path()), &clock).await;
+        clock.sleeping_until(start + BACKUP_INTERVAL).await;
+
+        service.update_clusters(update_request(60)).await.unwrap();
+        service.export_backup().await.unwrap();
+        service.export_backup().await.unwrap();
+
+        let newest = dir.path().join(FileStore::BACKUP_FILE_NAME);
+        let json = std::fs::read(&newest).unwrap();
+        std::fs::write(&newest, &json[..json.len() / 2]).unwrap();
+
+        let restored = self::service(Some(dir.path()), &clock).await;
+        assert_eq!(remaining_ttl(&restored, &clock).await, Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn failed_backups_are_retried_with_backoff() {
+        let dir = temp_dir();
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let service = service(Some(dir.path()), &clock).await;
+        let health = service.backup_health();
+        clock.sleeping_until(start + BACKUP_INTERVAL).await;
+
+        std::fs::remove_dir_all(dir.path()).unwrap();
+        clock.advance(BACKUP_INTERVAL);
+        let failed_at = clock.now();
+        clock.sleeping_until(failed_at + Duration::from_secs(1)).await;
+        assert!(*health.subscribe().borrow());
+
+        clock.advance(Duration::from_secs(1));
+        clock.sleeping_until(failed_at + Duration::from_secs(3)).await;
+        assert_eq!(health.failures(), 2);
+
+        std::fs::create_dir_all(dir.path()).unwrap();
+        clock.advance(Duration::from_secs(2));
+        clock.sleeping_until(start + BACKUP_INTERVAL * 2).await;
+        assert!(!*health.subscribe().borrow());
+        assert_eq!(health.failures(), 2);
+        assert!(dir.path().join(FileStore::BACKUP_FILE_NAME).exists());
+    }
+
+    fn replication_token() -> ReplicationToken {
+        "secret".parse().unwrap()
+    }
+
+    async fn bind() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        (listener, url)
+    }
+
+    // serves the discovery service on a free localhost port and returns its URL
+    async fn serve(service: &DiscoveryService) -> String {
+        let (listener, url) = bind().await;
+        tokio::spawn(
+            Server::builder()
+                .add_service(ClusterServer::new(service.clone()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        url
+    }
+
+    // the replication service has its own listener, like in production
+    async fn serve_replication(service: &DiscoveryService) -> String {
+        let (listener, url) = bind().await;
+        serve_replication_listener(listener, service);
+        url
+    }
+
+    fn serve_replication_listener(listener: TcpListener, service: &DiscoveryService) {
+        tokio::spawn(
+            Server::builder()
+                .add_service(ReplicationServer::with_interceptor(
+                    service.clone(),
+                    replication_token(),
+                ))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+    }
+
+    // the replication listeners are bound first, so every instance is configured with the URLs of all others, the
+    // URLs of their discovery services are returned
+    async fn serve_peers(clock: &Arc<ManualClock>, count: usize) -> Vec<(DiscoveryService, String)> {
+        let mut listeners = Vec::new();
+        for _ in 0..count {
+            listeners.push(bind().await);
+        }
+        let urls = listeners.iter().map(|(_, url)| url.clone()).collect::<Vec<_>>();
+
+        let mut peers = Vec::new();
+        for (listener, url) in listeners {
+            let options = ServiceOptions {
+                peers: urls.iter().filter(|peer_url| **peer_url != url).cloned().collect(),
+                replication_token: Some(replication_token()),
+                ..Default::default()
+            };
+            let service = DiscoveryService::new(options, clock.clone()).await.unwrap();
+            serve_replication_listener(listener, &service);
+            let url = serve(&service).await;
+            peers.push((service, url));
+        }
+        peers
+    }
+
+    fn standby_options(replication_url: &str) -> ServiceOptions {
+        ServiceOptions {
+            replicate_from: Some(replication_url.to_string()),
+            primary_endpoint: Some("primary:3000".to_string()),
+            replication_token: Some(replication_token()),
+            ..Default::default()
+        }
+    }
+
+    // replication happens in the background, so the affiliates are polled until they match
+    async fn wait_for_listed(url: &str, matches: impl Fn(&[discovery_api::Affiliate]) -> bool) {
+        let mut client = ClusterClient::connect(url.to_string()).await.unwrap();
+        for _ in 0..500 {
+            let request = ListRequest {
+                cluster_id: "cluster".to_string(),
+            };
+            let affiliates = match client.list(request).await {
+                Ok(response) => response.into_inner().affiliates,
+                Err(_) => Vec::new(),
+            };
+            if matches(&affiliates) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("affiliates of {url} weren't replicated");
+    }
+
+    async fn wait_for_affiliates(url: &str, expected: &[&str]) {
+        wait_for_listed(url, |affiliates| {
+            let mut affiliates = affiliates
+                .iter()
+                .map(|affiliate| affiliate.id.as_str())
+                .collect::<Vec<_>>();
+            affiliates.sort();
+            affiliates == expected
+        })
+        .await;
+    }
+
+    fn peer_update(affiliate_id: &str, data: &[u8]) -> AffiliateUpdateRequest {
+        AffiliateUpdateRequest {
+            affiliate_id: affiliate_id.to_string(),
+            affiliate_data: Some(data.to_vec()),
+            ..update_request(60)
+        }
+    }
+
+    fn hello_request() -> HelloRequest {
+        HelloRequest {
+            cluster_id: "cluster".to_string(),
+            client_version: "v1.9.0".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn compat_mode_rejects_too_many_endpoints() {
+        let options = ServiceOptions {
+            mode: Mode::Compat,
+            limits: Limits::for_mode(Mode::Compat),
+            ..Default::default()
+        };
+        let service = DiscoveryService::new(options, ManualClock::new()).await.unwrap();
+        let mut client = ClusterClient::connect(serve(&service).await).await.unwrap();
+
+        let request = AffiliateUpdateRequest {
+            affiliate_endpoints: (0..65).map(|i| vec![i]).collect(),
+            ..update_request(60)
+        };
+        let status = client.affiliate_update(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "too many endpoints");
+    }
+
+    #[tokio::test]
+    async fn standby_replicates_primary_and_redirects_writes() {
+        let clock = ManualClock::new();
+        let primary = service(None, &clock).await;
+        primary.update_clusters(update_request(60)).await.unwrap();
+        let primary_url = serve(&primary).await;
+        let replication_url = serve_replication(&primary).await;
+
+        let standby = DiscoveryService::new(standby_options(&replication_url), clock.clone())
+            .await
+            .unwrap();
+        let standby_url = serve(&standby).await;
+        wait_for_affiliates(&standby_url, &["affiliate"]).await;
+        assert_eq!(remaining_ttl(&standby, &clock).await, Duration::from_secs(60));
+
+        let mut watcher = watch(&standby).await;
+        watcher.recv().await.unwrap().unwrap();
+        primary
+            .update_clusters(AffiliateUpdateRequest {
+                affiliate_id: "second".to_string(),
+                ..update_request(60)
+            })
+            .await
+            .unwrap();
+        let update = watcher.recv().await.unwrap().unwrap();
+        assert_eq!(update.affiliates[0].id, "second");
+
+        let mut primary_client = ClusterClient::connect(primary_url.clone()).await.unwrap();
+        primary_client
+            .affiliate_delete(AffiliateDeleteRequest {
+                cluster_id: "cluster".to_string(),
+                affiliate_id: "affiliate".to_string(),
+            })
+            .await
+            .unwrap();
+        let deletion = watcher.recv().await.unwrap().unwrap();
+        assert!(deletion.deleted);
+        wait_for_affiliates(&standby_url, &["second"]).await;
+
+        let mut standby_client = ClusterClient::connect(standby_url).await.unwrap();
+        let status = standby_client.affiliate_update(update_request(60)).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+
+        let hello = standby_client.hello(hello_request()).await.unwrap().into_inner();
+        assert_eq!(hello.redirect.unwrap().endpoints, vec!["primary:3000".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn replication_is_only_served_with_the_token_on_its_own_listener() {
+        let clock = ManualClock::new();
+        let service = service(None, &clock).await;
+        let subscribe = |url: String, token: ReplicationToken| async move {
+            let mut client = ReplicationClient::connect(url).await.unwrap();
+            client.subscribe(token.request(SubscribeRequest {})).await.map(|_| ())
+        };
+
+        let status = subscribe(serve(&service).await, replication_token()).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unimplemented);
+
+        let replication_url = serve_replication(&service).await;
+        let status = subscribe(replication_url.clone(), "guess".parse().unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        let status = ReplicationClient::connect(replication_url.clone())
+            .await
+            .unwrap()
+            .promote(PromoteRequest {})
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        subscribe(replication_url.clone(), replication_token()).await.unwrap();
+
+        // instances without the token can't replicate at all
+        let options = ServiceOptions {
+            peers: vec![replication_url],
+            ..Default::default()
+        };
+        assert!(DiscoveryService::new(options, clock.clone()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn promoted_standby_accepts_writes() {
+        let clock = ManualClock::new();
+        let primary = service(None, &clock).await;
+        primary.update_clusters(update_request(60)).await.unwrap();
+        let replication_url = serve_replication(&primary).await;
+
+        let standby = DiscoveryService::new(standby_options(&replication_url), clock.clone())
+            .await
+            .unwrap();
+        let standby_url = serve(&standby).await;
+        wait_for_affiliates(&standby_url, &["affiliate"]).await;
+
+        ReplicationClient::connect(serve_replication(&standby).await)
+            .await
+            .unwrap()
+            .promote(replication_token().request(PromoteRequest {}))
+            .await
+            .unwrap();
+
+        let mut standby_client = ClusterClient::connect(standby_url.clone()).await.unwrap();
+        let hello = standby_client.hello(hello_request()).await.unwrap().into_inner();
+        assert!(hello.redirect.is_none());
+
+        standby_client
+            .affiliate_update(AffiliateUpdateRequest {
+                affiliate_id: "second".to_string(),
+                ..update_request(60)
+            })
+            .await
+            .unwrap();
+        wait_for_affiliates(&standby_url, &["affiliate", "second"]).await;
+    }
+
+    #[tokio::test]
+    async fn standby_keeps_its_state_when_a_primary_without_persistence_restarts() {
+        let clock = ManualClock::new();
+        let options = ServiceOptions {
+            limits: Limits {
+                max_ttl: Duration::from_secs(30),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let standby = DiscoveryService::new(options, clock.clone()).await.unwrap();
+        let affiliate_ids = || async {
+            let cluster = standby.get_cluster(&"cluster".to_string()).await.unwrap();
+            let cluster = cluster.lock().await;
+            let mut affiliate_ids = cluster
+                .get_affiliates()
+                .await
+                .into_iter()
+                .map(|affiliate| discovery_api::Affiliate::from(affiliate).id)
+                .collect::<Vec<_>>();
+            affiliate_ids.sort();
+            affiliate_ids
+        };
+
+        let primary = service(None, &clock).await;
+        primary.update_clusters(update_request(60)).await.unwrap();
+        let mut primary_instance = None;
+        standby
+            .apply_snapshot(primary.replication_snapshot().await, &mut primary_instance)
+            .await;
+        assert_eq!(affiliate_ids().await, ["affiliate"]);
+
+        // the restarted primary has lost the affiliate, until it re-registered
+        let restarted = service(None, &clock).await;
+        restarted.update_clusters(peer_update("second", b"data")).await.unwrap();
+        standby
+            .apply_snapshot(restarted.replication_snapshot().await, &mut primary_instance)
+            .await;
+        assert_eq!(affiliate_ids().await, ["affiliate", "second"]);
+
+        // every affiliate had to re-register within the maximum TTL
+        clock.advance(Duration::from_secs(30));
+        standby
+            .apply_snapshot(restarted.replication_snapshot().await, &mut primary_instance)
+            .await;
+        assert_eq!(affiliate_ids().await, ["second"]);
+
+        // a persistent primary restores its state, so its snapshots always replace the state of the standby
+        let mut persistent = primary.replication_snapshot().await;
+        persistent.persistent = true;
+        standby.apply_snapshot(persistent, &mut primary_instance).await;
+        assert_eq!(affiliate_ids().await, ["affiliate"]);
+    }
+
+    #[tokio::test]
+    async fn peers_converge_on_the_latest_write() {
+        let clock = ManualClock::new();
+        let peers = serve_peers(&clock, 3).await;
+        let mut clients = Vec::new();
+        for (_, url) in &peers {
+            clients.push(ClusterClient::connect(url.clone()).await.unwrap());
+        }
+
+        clients[0]
+            .affiliate_update(peer_update("first", b"first"))
+            .await
+            .unwrap();
+        clients[1]
+            .affiliate_update(peer_update("second", b"second"))
+            .await
+            .unwrap();
+        for (_, url) in &peers {
+            wait_for_affiliates(url, &["first", "second"]).await;
+        }
+
+        // watchers receive changes which originated on another instance
+        let mut watcher = watch(&peers[2].0).await;
+        watcher.recv().await.unwrap().unwrap();
+        clock.advance(Duration::from_secs(1));
+        clients[0]
+            .affiliate_update(peer_update("first", b"newer"))
+            .await
+            .unwrap();
+        let update = watcher.recv().await.unwrap().unwrap();
+        assert_eq!(update.affiliates[0].id, "first");
+        assert_eq!(update.affiliates[0].data, b"newer");
+
+        // concurrent writes of the same affiliate end up with the latest one everywhere
+        clock.advance(Duration::from_secs(1));
+        clients[0]
+            .affiliate_update(peer_update("second", b"older"))
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(1));
+        clients[2]
+            .affiliate_update(peer_update("second", b"latest"))
+            .await
+            .unwrap();
+        for (_, url) in &peers {
+            wait_for_listed(url, |affiliates| {
+                affiliates
+                    .iter()
+                    .any(|affiliate| affiliate.id == "second" && affiliate.data == b"latest")
+            })
+            .await;
+        }
+
+        clock.advance(Duration::from_secs(1));
+        clients[1]
+            .affiliate_delete(AffiliateDeleteRequest {
+                cluster_id: "cluster".to_string(),
+                affiliate_id: "first".to_string(),
+            })
+            .await
+            .unwrap();
+        for (_, url) in &peers {
+            wait_for_affiliates(url, &["second"]).await;
+        }
+    }
+}
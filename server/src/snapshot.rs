@@ -0,0 +1,479 @@
+use anyhow::{bail, ensure};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use prost::Message;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    time::SystemTime,
+};
+
+// Binary snapshot format, all integers are little endian:
+//
+//   magic "TDSB" | version: u8 | compression: u8 | CRC32 of the payload: u32 | payload length: u64 | payload
+//
+// The uncompressed payload is a length-delimited SnapshotHeader followed by one length-delimited ClusterBackup per
+// cluster.
+const MAGIC: &[u8; 4] = b"TDSB";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 1 + 4 + 8;
+
+const COMPRESSION_NONE: u8 = 0;
+const COMPRESSION_GZIP: u8 = 1;
+
+#[derive(Clone, PartialEq, Message)]
+struct SnapshotHeader {
+    #[prost(message, optional, tag = "1")]
+    snapshot_time: Option<prost_types::Timestamp>,
+    #[prost(uint64, tag = "2")]
+    journal_generation: u64,
+}
+
+/// Restorable state of a cluster.
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct ClusterBackup {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(message, repeated, tag = "2")]
+    pub affiliates: Vec<AffiliateBackup>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct AffiliateBackup {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub data: Vec<u8>,
+    #[prost(message, repeated, tag = "3")]
+    pub endpoints: Vec<EndpointBackup>,
+    #[prost(message, optional, tag = "4")]
+    pub expiration: Option<prost_types::Timestamp>,
+    // wall-clock time of the last write, the latest write wins when instances merge their states
+    #[prost(message, optional, tag = "5")]
+    pub updated: Option<prost_types::Timestamp>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct EndpointBackup {
+    #[prost(bytes = "vec", tag = "1")]
+    pub data: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub expiration: Option<prost_types::Timestamp>,
+}
+
+/// State of all clusters at one point in time.
+#[derive(Debug)]
+pub(crate) struct Snapshot {
+    // wall-clock time the expirations are relative to, missing in the oldest JSON backups
+    pub snapshot_time: Option<SystemTime>,
+    // first journal segment with changes after the snapshot
+    pub journal_generation: u64,
+    pub clusters: Vec<ClusterBackup>,
+}
+
+pub(crate) fn encode(snapshot: &Snapshot) -> anyhow::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    SnapshotHeader {
+        snapshot_time: snapshot.snapshot_time.map(Into::into),
+        journal_generation: snapshot.journal_generation,
+    }
+    .encode_length_delimited(&mut payload)?;
+    for cluster in &snapshot.clusters {
+        cluster.encode_length_delimited(&mut payload)?;
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&payload)?;
+    let payload = encoder.finish()?;
+
+    let mut contents = Vec::with_capacity(HEADER_LEN + payload.len());
+    contents.extend_from_slice(MAGIC);
+    contents.push(VERSION);
+    contents.push(COMPRESSION_GZIP);
+    contents.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    contents.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    contents.extend_from_slice(&payload);
+
+    Ok(contents)
+}
+
+pub(crate) fn decode(contents: &[u8]) -> anyhow::Result<Snapshot> {
+    ensure!(
+        contents.len() >= HEADER_LEN && contents.starts_with(MAGIC),
+        "not a binary snapshot"
+    );
+
+    let version = contents[4];
+    ensure!(version == VERSION, "unsupported snapshot version {version}");
+
+    let compression = contents[5];
+    let checksum = u32::from_le_bytes(contents[6..10].try_into()?);
+    let length = u64::from_le_bytes(contents[10..18].try_into()?);
+    let payload = &contents[HEADER_LEN..];
+    ensure!(
+        payload.len() as u64 == length,
+        "snapshot is truncated: {} of {} bytes",
+        payload.len(),
+        length
+    );
+    ensure!(crc32fast::hash(payload) == checksum, "snapshot checksum mismatch");
+
+    let payload = match compression {
+        COMPRESSION_NONE => payload.to_vec(),
+        COMPRESSION_GZIP => {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(payload).read_to_end(&mut decompressed)?;
+            decompressed
+        }
+        compression => bail!("unsupported snapshot compression {compression}"),
+    };
+
+    let mut payload = payload.as_slice();
+    let header = SnapshotHeader::decode_length_delimited(&mut payload)?;
+    let mut clusters = Vec::new();
+    while !payload.is_empty() {
+        clusters.push(ClusterBackup::decode_length_delimited(&mut payload)?);
+    }
+
+    Ok(Snapshot {
+        snapshot_time: header.snapshot_time.map(SystemTime::try_from).transpose()?,
+        journal_generation: header.journal_generation,
+        clusters,
+    })
+}
+
+// JSON backups written by earlier versions, only read to migrate them
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonBackup {
+    Snapshot {
+        snapshot_time: SystemTime,
+        clusters: Vec<JsonCluster>,
+    },
+    // written before backups carried a snapshot time
+    Legacy(Vec<JsonCluster>),
+}
+
+#[derive(Deserialize)]
+struct JsonCluster {
+    id: String,
+    affiliates: HashMap<String, JsonAffiliate>,
+}
+
+#[derive(Deserialize)]
+struct JsonAffiliate {
+    id: String,
+    data: Vec<u8>,
+    endpoints: Vec<JsonEndpoint>,
+    expiration: SystemTime,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonEndpoint {
+    Expiring { data: Vec<u8>, expiration: SystemTime },
+    // written before endpoints expired on their own, they live as long as their affiliate
+    Raw(Vec<u8>),
+}
+
+impl From<JsonCluster> for ClusterBackup {
+    fn from(cluster: JsonCluster) -> Self {
+        ClusterBackup {
+            id: cluster.id,
+            affiliates: cluster
+                .affiliates
+                .into_values()
+                .map(|affiliate| AffiliateBackup {
+                    id: affiliate.id,
+                    data: affiliate.data,
+                    endpoints: affiliate
+                        .endpoints
+                        .into_iter()
+                        .map(|endpoint| match endpoint {
+                            JsonEndpoint::Expiring { data, expiration } => EndpointBackup {
+                                data,
+                                expiration: Some(expiration.into()),
+                            },
+                            JsonEndpoint::Raw(data) => EndpointBackup {
+                                data,
+                                expiration: Some(affiliate.expiration.into()),
+                            },
+                        })
+                        .collect(),
+                    expiration: Some(affiliate.expiration.into()),
+                    updated: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+pub(crate) fn decode_json(contents: &[u8]) -> anyhow::Result<Snapshot> {
+    let (snapshot_time, clusters) = match serde_json::from_slice(contents)? {
+        JsonBackup::Snapshot {
+            snapshot_time,
+            clusters,
+        } => (Some(snapshot_time), clusters),
+        JsonBackup::Legacy(clusters) => (None, clusters),
+    };
+
+    Ok(Snapshot {
+        snapshot_time,
+        journal_generation: 0,
+        clusters: clusters.into_iter().map(Into::into).collect(),
+    })
+}
+
+// State snapshots of the upstream discovery service (github.com/siderolabs/discovery-service, api/storage), written
+// as gzip'd protobuf. They have no snapshot time, expirations are absolute wall-clock times.
+
+#[derive(Clone, PartialEq, Message)]
+struct StateSnapshot {
+    #[prost(message, repeated, tag = "1")]
+    clusters: Vec<ClusterSnapshot>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ClusterSnapshot {
+    #[prost(string, tag = "1")]
+    id: String,
+    #[prost(message, repeated, tag = "2")]
+    affiliates: Vec<AffiliateSnapshot>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct AffiliateSnapshot {
+    #[prost(string, tag = "1")]
+    id: String,
+    #[prost(message, optional, tag = "2")]
+    expiration: Option<prost_types::Timestamp>,
+    #[prost(bytes = "vec", tag = "3")]
+    data: Vec<u8>,
+    #[prost(message, repeated, tag = "4")]
+    endpoints: Vec<EndpointSnapshot>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct EndpointSnapshot {
+    #[prost(message, optional, tag = "1")]
+    expiration: Option<prost_types::Timestamp>,
+    #[prost(bytes = "vec", tag = "2")]
+    data: Vec<u8>,
+}
+
+const GZIP_MAGIC: &[u8; 2] = &[0x1f, 0x8b];
+
+impl From<&ClusterBackup> for ClusterSnapshot {
+    fn from(cluster: &ClusterBackup) -> Self {
+        ClusterSnapshot {
+            id: cluster.id.clone(),
+            affiliates: cluster
+                .affiliates
+                .iter()
+                .map(|affiliate| AffiliateSnapshot {
+                    id: affiliate.id.clone(),
+                    expiration: affiliate.expiration,
+                    data: affiliate.data.clone(),
+                    endpoints: affiliate
+                        .endpoints
+                        .iter()
+                        .map(|endpoint| EndpointSnapshot {
+                            expiration: endpoint.expiration,
+                            data: endpoint.data.clone(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<ClusterSnapshot> for ClusterBackup {
+    fn from(cluster: ClusterSnapshot) -> Self {
+        ClusterBackup {
+            id: cluster.id,
+            affiliates: cluster
+                .affiliates
+                .into_iter()
+                .map(|affiliate| AffiliateBackup {
+                    id: affiliate.id,
+                    data: affiliate.data,
+                    endpoints: affiliate
+                        .endpoints
+                        .into_iter()
+                        .map(|endpoint| EndpointBackup {
+                            data: endpoint.data,
+                            expiration: endpoint.expiration,
+                        })
+                        .collect(),
+                    expiration: affiliate.expiration,
+                    updated: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Encodes a snapshot which the upstream discovery service can restore.
+pub(crate) fn encode_upstream(snapshot: &Snapshot) -> anyhow::Result<Vec<u8>> {
+    let state = StateSnapshot {
+        clusters: snapshot.clusters.iter().map(Into::into).collect(),
+    };
+
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&state.encode_to_vec())?;
+    Ok(encoder.finish()?)
+}
+
+/// Decodes a snapshot of the upstream discovery service, uncompressed ones are accepted as well.
+pub(crate) fn decode_upstream(contents: &[u8]) -> anyhow::Result<Snapshot> {
+    let state = if contents.starts_with(GZIP_MAGIC) {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(contents).read_to_end(&mut decompressed)?;
+        StateSnapshot::decode(decompressed.as_slice())?
+    } else {
+        StateSnapshot::decode(contents)?
+    };
+
+    Ok(Snapshot {
+        snapshot_time: None,
+        journal_generation: 0,
+        clusters: state.clusters.into_iter().map(Into::into).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn snapshot() -> Snapshot {
+        let expiration = SystemTime::now() + Duration::from_secs(60);
+        Snapshot {
+            snapshot_time: Some(SystemTime::now()),
+            journal_generation: 3,
+            clusters: vec![ClusterBackup {
+                id: "cluster".to_string(),
+                affiliates: vec![AffiliateBackup {
+                    id: "affiliate".to_string(),
+                    data: vec![0xab; 1024],
+                    endpoints: vec![EndpointBackup {
+                        data: vec![0xcd; 32],
+                        expiration: Some(expiration.into()),
+                    }],
+                    expiration: Some(expiration.into()),
+                    updated: None,
+                }],
+            }],
+        }
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let snapshot = snapshot();
+        let decoded = decode(&encode(&snapshot).unwrap()).unwrap();
+
+        assert_eq!(decoded.snapshot_time, snapshot.snapshot_time);
+        assert_eq!(decoded.journal_generation, snapshot.journal_generation);
+        assert_eq!(decoded.clusters, snapshot.clusters);
+    }
+
+    #[test]
+    fn corrupted_snapshot_is_rejected() {
+        let mut contents = encode(&snapshot()).unwrap();
+
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        assert!(decode(&contents).unwrap_err().to_string().contains("checksum"));
+
+        contents[4] = VERSION + 1;
+        assert!(decode(&contents).unwrap_err().to_string().contains("version"));
+
+        assert!(decode(&contents[..HEADER_LEN]).is_err());
+        assert!(decode(b"[]").is_err());
+    }
+
+    #[test]
+    fn json_backups_are_migrated() {
+        let expiration = SystemTime::now() + Duration::from_secs(60);
+        let cluster = serde_json::json!({
+            "id": "cluster",
+            "affiliates": {
+                "affiliate": {
+                    "id": "affiliate",
+                    "data": [1, 2, 3],
+                    "endpoints": [{ "data": [4], "expiration": expiration }],
+                    "expiration": expiration,
+                }
+            }
+        });
+
+        let legacy = decode_json(serde_json::json!([cluster]).to_string().as_bytes()).unwrap();
+        assert!(legacy.snapshot_time.is_none());
+        assert_eq!(legacy.clusters[0].affiliates[0].data, vec![1, 2, 3]);
+        assert_eq!(
+            legacy.clusters[0].affiliates[0].endpoints[0].expiration,
+            Some(expiration.into())
+        );
+
+        let json = serde_json::json!({ "snapshot_time": SystemTime::now(), "clusters": [cluster] });
+        let snapshot = decode_json(json.to_string().as_bytes()).unwrap();
+        assert!(snapshot.snapshot_time.is_some());
+        assert_eq!(snapshot.clusters[0].id, "cluster");
+    }
+
+    #[test]
+    fn baseline_json_backups_are_migrated() {
+        // exactly as serialized by the first releases, endpoints were plain byte arrays
+        let contents = br#"[{"id":"cluster","affiliates":{"affiliate":{"id":"affiliate","data":[1,2,3],"endpoints":[[10,0,0,1],[10,0,0,2]],"expiration":{"secs_since_epoch":1700000000,"nanos_since_epoch":500}}}}]"#;
+
+        let snapshot = decode_json(contents).unwrap();
+        let affiliate = &snapshot.clusters[0].affiliates[0];
+        let expiration = SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 500);
+        assert_eq!(affiliate.expiration, Some(expiration.into()));
+        assert_eq!(
+            affiliate.endpoints,
+            vec![
+                EndpointBackup {
+                    data: vec![10, 0, 0, 1],
+                    expiration: Some(expiration.into()),
+                },
+                EndpointBackup {
+                    data: vec![10, 0, 0, 2],
+                    expiration: Some(expiration.into()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn upstream_snapshots_round_trip() {
+        let snapshot = snapshot();
+        let decoded = decode_upstream(&encode_upstream(&snapshot).unwrap()).unwrap();
+
+        assert!(decoded.snapshot_time.is_none());
+        assert_eq!(decoded.clusters, snapshot.clusters);
+    }
+
+    #[test]
+    fn upstream_fields_are_mapped() {
+        // StateSnapshot { clusters: [{ id: "c", affiliates: [{ id: "a", expiration: { seconds: 100 }, data: [1] }] }] }
+        let state = [
+            0x0a, 0x0f, 0x0a, 0x01, b'c', 0x12, 0x0a, 0x0a, 0x01, b'a', 0x12, 0x02, 0x08, 0x64, 0x1a, 0x01, 0x01,
+        ];
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&state).unwrap();
+
+        for contents in [state.to_vec(), encoder.finish().unwrap()] {
+            let cluster = decode_upstream(&contents).unwrap().clusters.remove(0);
+            assert_eq!(cluster.id, "c");
+            assert_eq!(cluster.affiliates[0].id, "a");
+            assert_eq!(cluster.affiliates[0].data, vec![1]);
+            assert_eq!(
+                cluster.affiliates[0].expiration,
+                Some(prost_types::Timestamp { seconds: 100, nanos: 0 })
+            );
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::bail;
+use discovery_api::tonic::async_trait;
+use std::{sync::Arc, time::SystemTime};
+use tracing::{info, warn};
+
+use crate::{
+    clock::Clock,
+    cluster::ClusterId,
+    file_state::FileStore,
+    redb_state::RedbStore,
+    service::ServiceOptions,
+    snapshot::{AffiliateBackup, Snapshot},
+    store::ClusterStore,
+};
+
+/// Storage backend of the cluster state.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum StateBackend {
+    /// Snapshots in the backup path, optionally with a journal of the changes in between
+    #[default]
+    File,
+    /// Embedded redb database in the backup path, every change is persisted before it's acknowledged
+    Redb,
+    /// Nothing is persisted, the state is lost on restart
+    Memory,
+}
+
+/// Persistence of the cluster state.
+///
+/// The clusters are served from memory, a store keeps a copy which is restored on startup. Changes of single
+/// affiliates are passed on as they happen, and the state of all clusters is saved periodically, so every backend can
+/// choose how much it persists immediately.
+#[async_trait]
+pub(crate) trait StateStore: Send + Sync {
+    /// Whether the state survives a restart. Changes and saves aren't passed on otherwise.
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    /// Whether every change is persisted before it's acknowledged, so the restored state is exactly the one before a
+    /// restart. Restored affiliates don't need a grace period then.
+    fn is_exact(&self) -> bool {
+        false
+    }
+
+    /// Returns the persisted state, or `None` if there is none.
+    async fn load(&self) -> anyhow::Result<Option<Snapshot>>;
+
+    /// Persists the state of all clusters and returns the number of saved clusters.
+    async fn save(&self, clusters: &ClusterStore) -> anyhow::Result<usize>;
+
+    /// Persists the complete state of an affiliate after an update.
+    async fn upsert_affiliate(&self, cluster_id: &ClusterId, affiliate: AffiliateBackup) -> anyhow::Result<()>;
+
+    async fn delete_affiliate(&self, cluster_id: &ClusterId, affiliate_id: &str) -> anyhow::Result<()>;
+
+    /// Removes affiliates which expired before `now` and returns their number.
+    async fn expire(&self, now: SystemTime) -> anyhow::Result<usize>;
+}
+
+/// Opens the backend selected in the options.
+pub(crate) async fn open(options: &ServiceOptions, clock: Arc<dyn Clock>) -> anyhow::Result<Arc<dyn StateStore>> {
+    let persistent = options.backup_path.is_some() || options.upstream_snapshot_path.is_some();
+
+    match options.state_backend {
+        StateBackend::File if persistent => Ok(Arc::new(FileStore::open(options, clock).await?)),
+        StateBackend::Redb => {
+            let Some(backup_path) = &options.backup_path else {
+                bail!("the redb backend requires a backup path");
+            };
+            if options.backup_keys.is_some() {
+                bail!("the redb backend doesn't support encryption, its keys contain the cluster IDs");
+            }
+            // every change is committed to the database, so the journal settings don't apply
+            if options.upstream_snapshot_path.is_some() {
+                warn!("Upstream snapshots are only supported by the file backend");
+            }
+
+            let path = backup_path.join(RedbStore::DATABASE_FILE_NAME);
+            Ok(Arc::new(RedbStore::open(path, clock).await?))
+        }
+        StateBackend::File | StateBackend::Memory => {
+            info!("State is kept in memory only");
+            Ok(Arc::new(MemoryStore))
+        }
+    }
+}
+
+/// Store which doesn't persist anything.
+pub(crate) struct MemoryStore;
+
+#[async_trait]
+impl StateStore for MemoryStore {
+    fn is_persistent(&self) -> bool {
+        false
+    }
+
+    async fn load(&self) -> anyhow::Result<Option<Snapshot>> {
+        Ok(None)
+    }
+
+    async fn save(&self, _clusters: &ClusterStore) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+
+    async fn upsert_affiliate(&self, _cluster_id: &ClusterId, _affiliate: AffiliateBackup) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn delete_affiliate(&self, _cluster_id: &ClusterId, _affiliate_id: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn expire(&self, _now: SystemTime) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    sync::Arc,
+};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{
+    clock::TimeBase,
+    cluster::{ClusterId, TalosCluster},
+    snapshot::ClusterBackup,
+};
+
+pub(crate) type SharedCluster = Arc<Mutex<TalosCluster>>;
+
+type Shard = HashMap<ClusterId, SharedCluster>;
+
+/// Concurrent map of clusters.
+///
+/// Clusters are spread over independently locked shards and every cluster has its own lock, so requests for
+/// unrelated clusters don't wait for each other.
+pub(crate) struct ClusterStore {
+    shards: Vec<RwLock<Shard>>,
+    hasher: RandomState,
+}
+
+impl ClusterStore {
+    const SHARD_COUNT: usize = 64;
+
+    pub fn new() -> Self {
+        Self {
+            shards: (0..Self::SHARD_COUNT).map(|_| RwLock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, cluster_id: &str) -> &RwLock<Shard> {
+        let index = self.hasher.hash_one(cluster_id) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    pub async fn get(&self, cluster_id: &str) -> Option<SharedCluster> {
+        self.shard(cluster_id).read().await.get(cluster_id).cloned()
+    }
+
+    pub async fn get_or_insert_with(&self, cluster_id: &str, create: impl FnOnce() -> TalosCluster) -> SharedCluster {
+        if let Some(cluster) = self.get(cluster_id).await {
+            return cluster;
+        }
+
+        self.shard(cluster_id)
+            .write()
+            .await
+            .entry(cluster_id.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(create())))
+            .clone()
+    }
+
+    pub async fn insert(&self, cluster: TalosCluster) {
+        self.shard(&cluster.id)
+            .write()
+            .await
+            .insert(cluster.id.clone(), Arc::new(Mutex::new(cluster)));
+    }
+
+    /// Returns all clusters without holding any lock afterwards.
+    pub async fn clusters(&self) -> Vec<SharedCluster> {
+        let mut clusters = Vec::new();
+        for shard in &self.shards {
+            clusters.extend(shard.read().await.values().cloned());
+        }
+        clusters
+    }
+
+    /// Returns the state of all clusters with expirations relative to `base`.
+    pub async fn backup(&self, base: &TimeBase) -> Vec<ClusterBackup> {
+        let mut backups = Vec::new();
+        for cluster in self.clusters().await {
+            backups.push(cluster.lock().await.backup(base));
+        }
+        backups
+    }
+
+    /// Removes all clusters which aren't used by a request and for which `keep` returns false.
+    /// Returns the number of removed and remaining clusters.
+    pub async fn retain(&self, mut keep: impl FnMut(&TalosCluster) -> bool) -> (usize, usize) {
+        let (mut removed, mut remaining) = (0, 0);
+
+        for shard in &self.shards {
+            let mut shard = shard.write().await;
+            let before_len = shard.len();
+
+            // references are only handed out under the shard lock, so a cluster only referenced by the shard
+            // can't be in use by a request
+            shard.retain(|_, cluster| {
+                Arc::strong_count(cluster) > 1 || cluster.try_lock().map_or(true, |cluster| keep(&cluster))
+            });
+
+            removed += before_len - shard.len();
+            remaining += shard.len();
+        }
+
+        (removed, remaining)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SystemClock, limits::Limits};
+
+    #[tokio::test]
+    async fn referenced_clusters_are_retained() {
+        let store = ClusterStore::new();
+        let cluster = store
+            .get_or_insert_with("cluster", || {
+                TalosCluster::new("cluster".to_string(), Limits::default(), Arc::new(SystemClock))
+            })
+            .await;
+
+        assert_eq!(store.retain(|_| false).await, (0, 1));
+
+        drop(cluster);
+        assert_eq!(store.retain(|_| false).await, (1, 0));
+        assert!(store.get("cluster").await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn clusters_are_created_once() {
+        let store = Arc::new(ClusterStore::new());
+
+        let tasks = (0..64)
+            .map(|i| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    let cluster_id = format!("cluster-{}", i % 8);
+                    let cluster = store
+                        .get_or_insert_with(&cluster_id, || {
+                            TalosCluster::new(cluster_id.clone(), Limits::default(), Arc::new(SystemClock))
+                        })
+                        .await;
+                    Arc::as_ptr(&cluster) as usize
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut clusters = Vec::new();
+        for task in tasks {
+            clusters.push(task.await.unwrap());
+        }
+        clusters.sort();
+        clusters.dedup();
+
+        assert_eq!(clusters.len(), 8);
+        assert_eq!(store.clusters().await.len(), 8);
+    }
+}
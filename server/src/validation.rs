@@ -0,0 +1,201 @@
+use discovery_api::{
+    tonic::{Code, Status},
+    AffiliateDeleteRequest, AffiliateUpdateRequest, HelloRequest, ListRequest, WatchRequest,
+};
+use std::time::Duration;
+use tonic_types::{ErrorDetails, FieldViolation, StatusExt};
+
+use crate::limits::{Limits, Mode};
+
+/// Fields of a request which failed validation, returned as `google.rpc.BadRequest` in the status details.
+#[derive(Debug, Default)]
+pub(crate) struct Violations(Vec<FieldViolation>);
+
+impl Violations {
+    fn add(&mut self, field: impl Into<String>, description: impl Into<String>) {
+        self.0.push(FieldViolation::new(field, description));
+    }
+
+    fn into_result(self) -> Result<(), Self> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl From<Violations> for Status {
+    fn from(violations: Violations) -> Self {
+        let message = violations
+            .0
+            .iter()
+            .map(|violation| violation.description.as_str())
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        Status::with_error_details(
+            Code::InvalidArgument,
+            message,
+            ErrorDetails::with_bad_request(violations.0),
+        )
+    }
+}
+
+/// Request validation shared by all RPCs of the discovery service.
+#[derive(Clone)]
+pub(crate) struct Validator {
+    mode: Mode,
+    limits: Limits,
+}
+
+impl Validator {
+    pub fn new(mode: Mode, limits: Limits) -> Self {
+        Self { mode, limits }
+    }
+
+    pub fn hello(&self, request: &HelloRequest) -> Result<(), Violations> {
+        let mut violations = Violations::default();
+        self.check_identifier(&mut violations, "cluster", &request.cluster_id);
+        violations.into_result()
+    }
+
+    pub fn watch(&self, request: &WatchRequest) -> Result<(), Violations> {
+        let mut violations = Violations::default();
+        self.check_identifier(&mut violations, "cluster", &request.cluster_id);
+        violations.into_result()
+    }
+
+    pub fn list(&self, request: &ListRequest) -> Result<(), Violations> {
+        let mut violations = Violations::default();
+        self.check_identifier(&mut violations, "cluster", &request.cluster_id);
+        violations.into_result()
+    }
+
+    pub fn affiliate_delete(&self, request: &AffiliateDeleteRequest) -> Result<(), Violations> {
+        let mut violations = Violations::default();
+        self.check_identifier(&mut violations, "cluster", &request.cluster_id);
+        self.check_identifier(&mut violations, "affiliate", &request.affiliate_id);
+        violations.into_result()
+    }
+
+    pub fn affiliate_update(&self, request: &AffiliateUpdateRequest) -> Result<(), Violations> {
+        let mut violations = Violations::default();
+        self.check_identifier(&mut violations, "cluster", &request.cluster_id);
+        self.check_identifier(&mut violations, "affiliate", &request.affiliate_id);
+
+        if let Some(affiliate_data) = &request.affiliate_data {
+            if affiliate_data.len() > self.limits.max_payload_length {
+                violations.add("affiliate_data", "affiliate data is too big");
+            }
+        }
+
+        if request.affiliate_endpoints.len() > self.limits.max_endpoints {
+            violations.add("affiliate_endpoints", "too many endpoints");
+        }
+        for (i, endpoint) in request.affiliate_endpoints.iter().enumerate() {
+            if endpoint.len() > self.limits.max_endpoint_length {
+                violations.add(format!("affiliate_endpoints[{i}]"), "affiliate endpoint is too big");
+            }
+        }
+
+        match request.ttl.map(Duration::try_from) {
+            None => violations.add("ttl", "ttl is required"),
+            Some(Err(_)) => violations.add("ttl", "ttl can't be negative"),
+            // XXX: custom extension
+            Some(Ok(ttl)) if ttl.is_zero() && self.mode == Mode::Hardened => {
+                violations.add("ttl", "ttl must be positive")
+            }
+            Some(Ok(ttl)) if ttl > self.limits.max_ttl => violations.add("ttl", "ttl is too large"),
+            Some(_) => (),
+        }
+
+        violations.into_result()
+    }
+
+    fn check_identifier(&self, violations: &mut Violations, kind: &str, id: &str) {
+        let field = format!("{kind}_id");
+
+        if id.is_empty() {
+            violations.add(field, format!("{kind} ID can't be empty"));
+        } else if id.len() > self.limits.max_identifier_length {
+            violations.add(field, format!("{kind} ID is too long"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::update_request;
+
+    fn field_violations(violations: Violations) -> Vec<FieldViolation> {
+        let status = Status::from(violations);
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        status.get_details_bad_request().unwrap().field_violations
+    }
+
+    #[test]
+    fn compat_mode_applies_upstream_limits() {
+        let compat = Validator::new(Mode::Compat, Limits::for_mode(Mode::Compat));
+        let hardened = Validator::new(Mode::Hardened, Limits::for_mode(Mode::Hardened));
+
+        let large_data = AffiliateUpdateRequest {
+            affiliate_data: Some(vec![0; 4096]),
+            ..update_request(60)
+        };
+        assert!(compat.affiliate_update(&large_data).is_err());
+        assert!(hardened.affiliate_update(&large_data).is_ok());
+
+        let zero_ttl = update_request(0);
+        assert!(compat.affiliate_update(&zero_ttl).is_ok());
+        assert!(hardened.affiliate_update(&zero_ttl).is_err());
+
+        let negative_ttl = update_request(-1);
+        assert!(compat.affiliate_update(&negative_ttl).is_err());
+        assert!(hardened.affiliate_update(&negative_ttl).is_err());
+
+        let long_ttl = update_request(60 * 60);
+        assert!(compat.affiliate_update(&long_ttl).is_err());
+        assert!(hardened.affiliate_update(&long_ttl).is_ok());
+
+        let mut many_endpoints = update_request(60);
+        many_endpoints.affiliate_endpoints = vec![vec![0; 8]; 65];
+        assert!(compat.affiliate_update(&many_endpoints).is_err());
+        assert!(hardened.affiliate_update(&many_endpoints).is_err());
+    }
+
+    #[test]
+    fn violations_are_reported_per_field() {
+        let validator = Validator::new(Mode::Compat, Limits::for_mode(Mode::Compat));
+        let mut request = update_request(60);
+        request.affiliate_id = String::new();
+        request.affiliate_endpoints = vec![vec![0; 8], vec![0; 64]];
+        request.ttl = None;
+
+        let fields = field_violations(validator.affiliate_update(&request).unwrap_err())
+            .into_iter()
+            .map(|violation| violation.field)
+            .collect::<Vec<_>>();
+        assert_eq!(fields, vec!["affiliate_id", "affiliate_endpoints[1]", "ttl"]);
+    }
+
+    #[test]
+    fn empty_cluster_id_is_rejected_by_every_rpc() {
+        let validator = Validator::new(Mode::Hardened, Limits::default());
+
+        let results = [
+            validator.hello(&HelloRequest::default()),
+            validator.watch(&WatchRequest::default()),
+            validator.list(&ListRequest::default()),
+            validator.affiliate_delete(&AffiliateDeleteRequest::default()),
+        ];
+
+        for result in results {
+            let violations = field_violations(result.unwrap_err());
+            assert_eq!(violations[0].field, "cluster_id");
+            assert_eq!(violations[0].description, "cluster ID can't be empty");
+        }
+    }
+}